@@ -1,41 +1,32 @@
-use crate::discord::{Client, Gateway, HttpRequest, Intent, Payload, LIBRARY_NAME, GatewayEvent};
+use crate::discord::{Client, Disconnect, DiscordError, Gateway, GatewayReader, HttpRequest, Intent};
 use futures::lock::Mutex;
-use tokio::sync::RwLock;
-use futures::future::{FutureExt, BoxFuture, Future};
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
+use futures::future::BoxFuture;
+use std::future::Future;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
-use std::ops::Deref;
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
+use tokio::time::{sleep, timeout, Duration};
+use futures_util::{stream::SplitSink, StreamExt};
 type WbSS = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-use rand::prelude::*;
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use tokio::net::TcpStream;
-use tokio_tungstenite::tungstenite;
-use tokio_tungstenite::{
-    connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
-};
+use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
 use crate::disc_objects;
-use std::pin::Pin;
+use crate::shard::{IdentifyQueue, ShardManager};
 
 pub type PinnedFuture = Box<dyn Fn(discord::GatewayEvent, BotClient) -> BoxFuture<'static, ()> + Send + Sync>;
 pub type EventMap = Arc<RwLock<HashMap<disc_objects::GatewayEventBinding, PinnedFuture>>>;
 
 pub type BotClient = Arc<Mutex<Client>>;
+pub type GatewaySink = Arc<Mutex<SplitSink<WbSS, Message>>>;
 use crate::discord;
 
-use serde_json::value::Value as SerdeValue;
-use SerdeValue::Null as SerdeNull;
-use SerdeValue::Number as SerdeNumber;
-use SerdeValue::Object as SerdeObject;
-use SerdeValue::String as SerdeString;
-use crate::disc_objects::Snowflake;
+// how long shutdown waits for handlers that are still running
+pub const HANDLER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
-type SerdeMap = serde_json::map::Map<String, SerdeValue>;
+// how long we wait before trying again when a connection fails before it gets going
+pub const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
 
 pub struct Bot
 {
@@ -43,12 +34,22 @@ pub struct Bot
     pub gateway_event_map: EventMap
 }
 
+// each shard has its own client, so gateway requests made from outside a handler go through here
+pub struct BotHandle {
+    shutdown: Arc<watch::Sender<bool>>,
+    handlers: Arc<RwLock<()>>,
+    shards: Vec<BotClient>,
+    running: JoinHandle<Result<(), DiscordError>>,
+}
+
 impl Bot
 {
-    pub fn new(mut api_ver: u32, token: String, intents: Vec<Intent>) -> Self {
+    pub fn new(api_ver: u32, token: String, intents: Vec<Intent>, presence: Option<disc_objects::GatewayPresence>) -> Self {
+        Bot::from_client(Client::new(api_ver, token, intents).presence(presence))
+    }
 
-        let client = Client::new(api_ver, token, intents);
-        let gateway_map = Arc::new(RwLock::new((HashMap::new())));
+    pub fn from_client(client: Client) -> Self {
+        let gateway_map = Arc::new(RwLock::new(HashMap::new()));
 
         Self {
             client: Arc::new(Mutex::new(client)),
@@ -57,40 +58,77 @@ impl Bot
     }
 
     pub async fn add_event(&self, gateway_event: disc_objects::GatewayEventBinding, function: PinnedFuture) {
-        let mut gateway_map = self.gateway_event_map.clone();
+        let gateway_map = self.gateway_event_map.clone();
 
         gateway_map.write().await.insert(gateway_event, function);
     }
 
-    pub async fn update_client_gateway(&self) {
+    pub async fn update_client_gateway(&self) -> Result<(), DiscordError> {
         let gateway_request = HttpRequest::str_new("/gateway/bot", self.client.clone());
 
-        self.client.lock().await.gateway = gateway_request
-            .await
-            .get()
-            .await
-            .expect("Failed to get gateway update request")
-            .json::<Gateway>()
-            .await
-            .expect("Failed to parse gateway update request into Gateway Struct");
+        let gateway = gateway_request.await.get().await?.json::<Gateway>().await?;
+
+        self.client.lock().await.gateway = gateway;
+        Ok(())
     }
 
-    pub async fn read(client: BotClient, read: &mut SplitStream<WbSS>, gateway_event_map: EventMap) {
+    pub async fn read(client: BotClient, read: &mut GatewayReader, write: &GatewaySink, gateway_event_map: EventMap) -> Disconnect {
         loop {
-            let payload = Gateway::read_next_payload(read).await;
-            println!("{:?}", payload);
+            let payload = match Gateway::read_next_payload(read).await {
+                Ok(payload) => payload,
+                Err(DiscordError::GatewayClosed(disconnect)) => return disconnect,
+                // one payload we can't make sense of shouldn't end the connection
+                Err(_) => continue,
+            };
             if payload.sequence.is_some() {
                 client.lock().await.sequence = payload.sequence;
             }
 
-            if !payload.data.is_none() {
+            if payload.gateway_type == disc_objects::GatewayEventBinding::HeartbeatOk {
+                client.lock().await.heartbeat_ack();
+            }
+
+            // discord wants a heartbeat right now instead of waiting for the interval
+            if payload.gateway_type == disc_objects::GatewayEventBinding::Heartbeat
+                && Client::send_heartbeat(client.clone(), write).await.is_err()
+            {
+                return Disconnect::Closed(None);
+            }
+
+            match &payload.data {
+                Some(discord::GatewayEvent::Ready(ready)) => {
+                    let mut client_guard = client.lock().await;
+                    client_guard.session_id = Some(ready.session_id.clone());
+                    client_guard.user_id = Some(ready.user.id.clone());
+                }
+                Some(discord::GatewayEvent::GuildMembersChunk(chunk)) => {
+                    client.lock().await.collect_member_chunk(chunk);
+                }
+                Some(discord::GatewayEvent::VoiceStateUpdate(voice_state)) => {
+                    client.lock().await.collect_voice_state(voice_state);
+                }
+                Some(discord::GatewayEvent::VoiceServerUpdate(voice_server)) => {
+                    client.lock().await.collect_voice_server(voice_server);
+                }
+                Some(discord::GatewayEvent::Reconnect) => return Disconnect::Reconnect,
+                Some(discord::GatewayEvent::InvalidSession(resumable)) => {
+                    return Disconnect::InvalidSession(*resumable)
+                }
+                _ => {}
+            }
+
+            if let Some(data) = payload.data {
 
-                let mut exists = gateway_event_map.clone().read().await.get(&payload.gateway_type).is_some();
+                let exists = gateway_event_map.read().await.get(&payload.gateway_type).is_some();
                 let map = gateway_event_map.clone();
                 let client = client.clone();
+                let gateway_type = payload.gateway_type;
                 if exists {
+                    let running = client.lock().await.handlers.clone().read_owned().await;
+
                     tokio::spawn(async move {
-                        map.clone().read().await.get(&payload.gateway_type).unwrap()(payload.data.unwrap(), client.clone()).await;
+                        map.read().await.get(&gateway_type).unwrap()(data, client.clone()).await;
+                        drop(running);
                     });
                 }
 
@@ -99,28 +137,226 @@ impl Bot
         }
     }
 
-    pub async fn elevate(&self) {
-        self.update_client_gateway().await;
+    // keeps a gateway connection alive until it is shut down or discord closes it for good
+    pub async fn run_gateway(client: BotClient, gateway_event_map: EventMap, identify_queue: Arc<IdentifyQueue>) -> Result<(), DiscordError> {
+        let stopped = Bot::keep_gateway_alive(client.clone(), gateway_event_map, identify_queue).await;
+
+        // nothing is left to answer requests still waiting on this shard, dropping them lets their handlers finish
+        client.lock().await.cancel_gateway_requests();
+        stopped
+    }
+
+    // anything that waits here can be cut short by a shutdown, the top of the loop then returns
+    async fn keep_gateway_alive(client: BotClient, gateway_event_map: EventMap, identify_queue: Arc<IdentifyQueue>) -> Result<(), DiscordError> {
+        let shutdown = client.lock().await.shutdown.clone();
+
+        loop {
+            if matches!(&shutdown, Some(shutdown) if *shutdown.borrow()) {
+                return Ok(());
+            }
+
+            let (resuming, shard_id) = {
+                let client_guard = client.lock().await;
+                (client_guard.session_id.is_some(), client_guard.shard_id())
+            };
+
+            // our turn to identify can be hours away, so we wait for it before opening a socket we'd have to keep alive
+            if !resuming && Bot::unless_shutdown(&shutdown, identify_queue.wait(shard_id)).await.is_none() {
+                continue;
+            }
+
+            let webstream = match Bot::unless_shutdown(&shutdown, discord::Client::connect(client.clone())).await {
+                Some(Ok((webstream, _))) => webstream,
+                Some(Err(error)) => {
+                    println!("Failed to connect to gateway, retrying: {}", error);
+                    Bot::unless_shutdown(&shutdown, sleep(RECONNECT_BACKOFF)).await;
+                    continue;
+                }
+                None => continue,
+            };
+            let (write, read) = webstream.split();
+            let write: GatewaySink = Arc::new(Mutex::new(write));
+            client.lock().await.sink = Some(write.clone());
+            let mut read = {
+                let client_guard = client.lock().await;
+                GatewayReader::new(read, client_guard.compress, client_guard.encoding)
+            };
+
+            match Bot::unless_shutdown(&shutdown, discord::Client::check_hello(client.clone(), &mut read)).await {
+                Some(Ok(_)) => {}
+                Some(Err(disconnect)) => {
+                    println!("Gateway closed before hello: {}", disconnect);
+                    Bot::unless_shutdown(&shutdown, sleep(RECONNECT_BACKOFF)).await;
+                    continue;
+                }
+                None => continue,
+            }
+
+            let sent = match resuming {
+                true => discord::Client::resume(client.clone(), &write).await,
+                false => discord::Client::identify(client.clone(), &write).await,
+            };
+
+            if let Err(error) = sent {
+                println!("Failed to identify with the gateway, retrying: {}", error);
+                Bot::unless_shutdown(&shutdown, sleep(RECONNECT_BACKOFF)).await;
+                continue;
+            }
+            println!("Successfully connected to discord. ");
+
+            let disconnect = tokio::select! {
+                disconnect = Bot::read(client.clone(), &mut read, &write, gateway_event_map.clone()) => disconnect,
+                disconnect = discord::Client::heartbeat(client.clone(), &write) => disconnect,
+                disconnect = discord::Client::wait_for_shutdown(shutdown.clone(), &write) => disconnect,
+            };
+
+            println!("Disconnected from gateway: {:?}", disconnect);
+
+            if disconnect.is_fatal() {
+                return match disconnect {
+                    Disconnect::Shutdown => Ok(()),
+                    Disconnect::Closed(Some(4004)) => Err(DiscordError::InvalidToken),
+                    disconnect => Err(DiscordError::GatewayClosed(disconnect)),
+                };
+            }
+
+            if !disconnect.is_resumable() {
+                let mut client_guard = client.lock().await;
+                client_guard.session_id = None;
+                client_guard.sequence = None;
+                client_guard.cancel_gateway_requests();
+            }
+
+            if let Disconnect::InvalidSession(_) = disconnect {
+                // discord asks for a random wait of 1-5 seconds before sending another identify/resume
+                let wait = Duration::from_secs_f64(1.0 + 4.0 * rand::random::<f64>());
+                Bot::unless_shutdown(&shutdown, sleep(wait)).await;
+            }
+        }
+    }
+
+    // None if the bot was asked to shut down before the future finished
+    async fn unless_shutdown<F: Future>(shutdown: &Option<watch::Receiver<bool>>, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = Client::shutdown_requested(shutdown.clone()) => None,
+        }
+    }
+
+    pub async fn elevate(&self) -> Result<BotHandle, DiscordError> {
+        self.update_client_gateway().await?;
+        Ok(self.start().await)
+    }
+
+    // runs every shard against whatever gateway the client already has
+    pub async fn start(&self) -> BotHandle {
+        let handlers = self.client.lock().await.handlers.clone();
+
+        let shard_manager = ShardManager::new(self.client.clone(), self.gateway_event_map.clone()).await;
+        let shutdown = shard_manager.shutdown.clone();
+        let shards = shard_manager.shards.clone();
+        let running = tokio::spawn(async move { shard_manager.run().await });
+
+        BotHandle {
+            shutdown,
+            handlers,
+            shards,
+            running,
+        }
+    }
+
+}
+
+impl BotHandle {
+    pub fn shards(&self) -> &[BotClient] {
+        &self.shards
+    }
+
+    pub fn shard_for_guild(&self, guild_id: &disc_objects::Snowflake) -> BotClient {
+        let shard_id = ShardManager::shard_id_for_guild(guild_id, self.shards.len() as u64);
+        self.shards[shard_id as usize].clone()
+    }
+
+    // one per shard, None until that shard's first heartbeat is acknowledged
+    pub async fn latencies(&self) -> Vec<Option<Duration>> {
+        let mut latencies = Vec::with_capacity(self.shards.len());
+
+        for shard in &self.shards {
+            latencies.push(shard.lock().await.latency());
+        }
+
+        latencies
+    }
+
+    // every shard shows the presence, and a shard that isn't connected sends it when it next identifies
+    pub async fn update_presence(
+        &self,
+        status: disc_objects::Status,
+        activities: Vec<disc_objects::BotActivity>,
+        afk: bool,
+        since: Option<u64>,
+    ) -> Result<(), DiscordError> {
+        let mut updated = Ok(());
+
+        for shard in &self.shards {
+            let sent = Client::update_presence(shard.clone(), status, activities.clone(), afk, since).await;
+
+            if updated.is_ok() {
+                updated = sent;
+            }
+        }
+
+        updated
+    }
+
+    pub async fn request_guild_members(
+        &self,
+        guild_id: disc_objects::Snowflake,
+        query: disc_objects::MemberQuery,
+        limit: u64,
+        presences: bool,
+    ) -> Result<Vec<disc_objects::GuildMember>, DiscordError> {
+        let shard = self.shard_for_guild(&guild_id);
+        Client::request_guild_members(shard, guild_id, query, limit, presences).await
+    }
+
+    pub async fn join_voice(
+        &self,
+        guild_id: disc_objects::Snowflake,
+        channel_id: disc_objects::Snowflake,
+        self_mute: bool,
+        self_deaf: bool,
+    ) -> Result<discord::VoiceSession, DiscordError> {
+        let shard = self.shard_for_guild(&guild_id);
+        Client::join_voice(shard, guild_id, channel_id, self_mute, self_deaf).await
+    }
 
-        let (webstream, _) = discord::Client::connect(self.client.clone()).await;
-        let (mut write, mut read) = webstream.split();
+    pub async fn leave_voice(&self, guild_id: disc_objects::Snowflake) -> Result<(), DiscordError> {
+        let shard = self.shard_for_guild(&guild_id);
+        Client::leave_voice(shard, guild_id).await
+    }
 
-        discord::Client::identify(self.client.clone(), &mut write).await;
-        discord::Client::check_hello(self.client.clone() ,&mut read).await;
-        println!("Successfully connected to discord. ");
+    // closes every shard normally, then gives handlers that are still running a chance to finish
+    pub async fn shutdown(self) -> Result<(), DiscordError> {
+        let _ = self.shutdown.send(true);
+        let stopped = BotHandle::join(self.running).await;
 
-        let client = self.client.clone();
-        tokio::spawn(async move {
-            discord::Client::heartbeat(client.clone(), &mut write).await;
-        });
+        if timeout(HANDLER_SHUTDOWN_TIMEOUT, self.handlers.write()).await.is_err() {
+            println!("Handlers were still running after {:?}, shutting down anyway", HANDLER_SHUTDOWN_TIMEOUT);
+        }
 
-        let client = self.client.clone();
-        let gateway_event_map = self.gateway_event_map.clone();
-        tokio::spawn(async move {
-            Bot::read(client.clone(), &mut read, gateway_event_map.clone()).await;
-        });
+        stopped
+    }
 
-        Client::keep_awake().await;
+    // waits for every shard to stop, which only happens on its own after a fatal close
+    pub async fn wait(self) -> Result<(), DiscordError> {
+        BotHandle::join(self.running).await
     }
 
+    async fn join(running: JoinHandle<Result<(), DiscordError>>) -> Result<(), DiscordError> {
+        match running.await {
+            Ok(stopped) => stopped,
+            Err(error) => std::panic::resume_unwind(error.into_panic()),
+        }
+    }
 }
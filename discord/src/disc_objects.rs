@@ -1,7 +1,10 @@
 use crate::bot;
 use crate::discord;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::fmt::Formatter;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 #[derive(Deserialize, Debug, Serialize, Clone, Hash, Eq, PartialEq)]
 #[serde(untagged)]
@@ -12,15 +15,35 @@ pub enum Snowflake {
 
 impl std::fmt::Display for Snowflake {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let write_string = match &self {
-            &Snowflake::Integer(integer_value) => integer_value.to_string(),
-            &Snowflake::String(string_value) => string_value.clone(),
+        let write_string = match self {
+            Snowflake::Integer(integer_value) => integer_value.to_string(),
+            Snowflake::String(string_value) => string_value.clone(),
         };
 
         write!(f, "{}", write_string)
     }
 }
 
+// milliseconds since the unix epoch at the start of 2015, snowflake timestamps count from here
+pub const DISCORD_EPOCH: u64 = 1420070400000;
+
+// discord won't bulk delete messages older than this
+pub const BULK_DELETE_MAX_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+impl Snowflake {
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Snowflake::Integer(integer_value) => Some(*integer_value),
+            Snowflake::String(string_value) => string_value.parse().ok(),
+        }
+    }
+
+    // when the snowflake was made, in milliseconds since the unix epoch
+    pub fn timestamp(&self) -> Option<u64> {
+        self.as_u64().map(|id| (id >> 22) + DISCORD_EPOCH)
+    }
+}
+
 #[derive(Deserialize, Debug, Serialize, Clone)]
 #[serde(untagged)]
 pub enum Nonce {
@@ -80,7 +103,7 @@ pub struct GuildMembersChunkEvent {
     pub chunk_count: u64,
     pub not_found: Option<Vec<String>>,
     pub presences: Option<Vec<PresenceUpdate>>,
-    pub nonce: Nonce,
+    pub nonce: Option<Nonce>,
 }
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
@@ -217,6 +240,48 @@ pub struct InviteCreateEvent {
     pub uses: u64
 }
 
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct Invite {
+    pub code: String,
+    pub guild: Option<InviteGuild>,
+    pub channel: Option<Channel>,
+    pub inviter: Option<User>,
+    pub target_type: Option<u64>,
+    pub target_user: Option<User>,
+    pub target_application: Option<Application>,
+    pub approximate_presence_count: Option<u64>,
+    pub approximate_member_count: Option<u64>,
+    pub expires_at: Option<String>,
+    pub stage_instance: Option<InviteStageInstance>,
+
+    // only sent when listing or creating a channel's invites
+    pub uses: Option<u64>,
+    pub max_uses: Option<u64>,
+    pub max_age: Option<u64>,
+    pub temporary: Option<bool>,
+    pub created_at: Option<String>,
+}
+
+// the part of a guild an invite shows to people not in it yet
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct InviteGuild {
+    pub id: Snowflake,
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub splash: Option<String>,
+    pub banner: Option<String>,
+    pub description: Option<String>,
+    pub features: Option<Vec<String>>,
+    pub verification_level: Option<u64>,
+    pub vanity_url_code: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct FollowedChannel {
+    pub channel_id: Snowflake,
+    pub webhook_id: Snowflake,
+}
+
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct InviteDeleteEvent {
     pub channel_id: Snowflake,
@@ -676,6 +741,43 @@ pub struct PresenceUpdate {
     pub client_status: ClientStatus,
 }
 
+#[derive(Clone, Copy, Deserialize, Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Online,
+    Dnd,
+    Idle,
+    Invisible,
+    Offline,
+}
+
+// which members a request guild members call asks for, an empty query means every member
+#[derive(Clone, Debug)]
+pub enum MemberQuery {
+    Query(String),
+    UserIds(Vec<Snowflake>),
+}
+
+// the only parts of an activity a bot is allowed to set
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct BotActivity {
+    pub name: String,
+
+    #[serde(rename = "type")]
+    pub activity_type: u64,
+
+    pub url: Option<String>,
+}
+
+// our own presence, sent in identify and with op 3
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct GatewayPresence {
+    pub since: Option<u64>,
+    pub activities: Vec<BotActivity>,
+    pub status: Status,
+    pub afk: bool,
+}
+
 #[derive(Clone, Deserialize, Debug, Serialize)]
 pub struct VoiceState {
     pub guild_id: Option<Snowflake>,
@@ -954,6 +1056,10 @@ pub enum GatewayEventBinding {
     VoiceStateUpdate,
     VoiceServerUpdate,
     WebhooksUpdate,
+
+    // any event we don't have a variant for yet, bound by its name e.g. "GUILD_SCHEDULED_EVENT_CREATE"
+    #[serde(untagged)]
+    Unknown(String),
 }
 
 #[derive(Clone, Deserialize, Debug, Serialize)]
@@ -970,6 +1076,113 @@ pub struct ReplyMessage {
     pub embeds: Option<Vec<Embed>>,
     pub message_reference: Option<Reply>,
     pub sticker_ids: Option<Vec<Snowflake>>,
+    // sent as multipart parts rather than in the json
+    #[serde(skip)]
+    pub files: Vec<FileUpload>,
+}
+
+// the fields to set when creating or modifying a guild channel, anything left as None is left alone
+#[derive(Clone, Deserialize, Debug, Serialize, Default)]
+pub struct ChannelSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub channel_type: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Snowflake>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nsfw: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rate_limit_per_user: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_limit: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permission_overwrites: Option<Vec<PermissionOverwrite>>,
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize, Default)]
+pub struct InviteSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temporary: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unique: Option<bool>,
+}
+
+// the most messages discord returns in one request
+pub const MESSAGE_PAGE_LIMIT: usize = 100;
+pub const REACTION_USERS_PAGE_LIMIT: usize = 100;
+
+// a channel's messages, fetched a page at a time as the stream is read
+#[derive(Clone, Debug)]
+pub struct MessageHistory {
+    pub channel_id: Snowflake,
+    client: bot::BotClient,
+}
+
+// the reactions on one message, the ids are all it needs so reaction events can use it without fetching
+#[derive(Clone, Debug)]
+pub struct MessageReactions {
+    pub channel_id: Snowflake,
+    pub message_id: Snowflake,
+    client: bot::BotClient,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReactionEmoji {
+    Unicode(String),
+    Custom { name: String, id: Snowflake },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum HistoryDirection {
+    Before,
+    After,
+    Around,
+}
+
+// a file to upload with a message, embeds can show it with "attachment://" followed by its name
+#[derive(Clone, Debug)]
+pub struct FileUpload {
+    pub name: String,
+    pub description: Option<String>,
+    pub content: Vec<u8>,
+}
+
+impl BotActivity {
+    pub fn new(name: &str, activity_type: u64) -> Self {
+        Self {
+            name: String::from(name),
+            activity_type,
+            url: None,
+        }
+    }
+
+    // only shown for streaming activities
+    pub fn url(mut self, url: &str) -> BotActivity {
+        self.url = Some(String::from(url));
+        self
+    }
+}
+
+impl GatewayPresence {
+    pub fn new(status: Status, activities: Vec<BotActivity>, afk: bool, since: Option<u64>) -> Self {
+        Self {
+            since,
+            activities,
+            status,
+            afk,
+        }
+    }
 }
 
 impl Reply {
@@ -994,6 +1207,7 @@ impl ReplyMessage {
             embeds: None,
             message_reference: None,
             sticker_ids: None,
+            files: Vec::new(),
         }
     }
 
@@ -1027,8 +1241,30 @@ impl ReplyMessage {
         self
     }
 
-    pub async fn send(&self, channel_id: Snowflake, client: bot::BotClient) -> Message {
-        let message = serde_json::json!({
+    pub fn add_file(mut self, name: &str, content: impl Into<Vec<u8>>) -> ReplyMessage {
+        self.files.push(FileUpload::new(name, content.into()));
+        self
+    }
+
+    // reads the whole file in now so a rate limited send can upload it again
+    pub async fn add_file_reader<R: AsyncRead + Unpin>(self, name: &str, mut reader: R) -> std::io::Result<ReplyMessage> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await?;
+
+        Ok(self.add_file(name, content))
+    }
+
+    // alt text for a file already added under that name
+    pub fn describe_file(mut self, name: &str, description: &str) -> ReplyMessage {
+        for file in self.files.iter_mut().filter(|file| file.name == name) {
+            file.description = Some(String::from(description));
+        }
+
+        self
+    }
+
+    pub async fn send(&self, channel_id: Snowflake, client: bot::BotClient) -> Result<Message, discord::DiscordError> {
+        let mut message = serde_json::json!({
             "content": self.content,
             "tts": self.tts,
             "embeds": self.embeds,
@@ -1044,16 +1280,52 @@ impl ReplyMessage {
         let extension = format!("/channels/{}/messages", channel_id);
         let payload = discord::HttpRequest::string_new(extension, client).await;
 
-        let response = payload.post(message).await;
-        let response_message: Message;
+        if self.files.is_empty() {
+            let response = payload.post(message).await?;
+            return Ok(response.json().await?);
+        }
+
+        message["attachments"] = FileUpload::attachments(&self.files);
+        let response = payload.post_multipart(message, &self.files).await?;
 
-        response_message = response
-            .expect("Failed to send message!")
-            .json()
-            .await
-            .expect("Failed to turn response into message! ");
+        Ok(response.json().await?)
+    }
+}
+
+impl FileUpload {
+    pub fn new(name: &str, content: Vec<u8>) -> Self {
+        Self {
+            name: String::from(name),
+            description: None,
+            content,
+        }
+    }
+
+    pub fn description(mut self, description: &str) -> FileUpload {
+        self.description = Some(String::from(description));
+        self
+    }
+
+    // what embeds use to point at this file instead of a url
+    pub fn attachment_url(&self) -> String {
+        format!("attachment://{}", self.name)
+    }
 
-        response_message
+    // each file's id is the n of the files[n] part it's uploaded in
+    pub fn attachments(files: &[FileUpload]) -> serde_json::Value {
+        let attachments: Vec<serde_json::Value> = files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                serde_json::json!({
+                    "id": index,
+                    "filename": file.name,
+                    "description": file.description
+                })
+            })
+            .collect();
+
+        serde_json::Value::from(attachments)
     }
 }
 
@@ -1082,6 +1354,7 @@ impl Embed {
         self
     }
 
+    // "attachment://name.png" shows a file uploaded with the message
     pub fn image(mut self, url: &str) -> Embed {
         self.image = Some(EmbedAttachment::new(url.to_string()));
 
@@ -1110,10 +1383,7 @@ impl Embed {
     }
 
     pub fn footer(mut self, text: &str, icon_url: Option<&str>) -> Embed {
-        let icon_url: Option<String> = match icon_url {
-            Some(value) => Some(value.to_string()),
-            None => None,
-        };
+        let icon_url: Option<String> = icon_url.map(|value| value.to_string());
 
         self.footer = Some(EmbedFooter::new(text.to_string(), icon_url));
 
@@ -1121,15 +1391,9 @@ impl Embed {
     }
 
     pub fn author(mut self, name: &str, icon_url: Option<&str>, url: Option<&str>) -> Embed {
-        let icon_url: Option<String> = match icon_url {
-            Some(value) => Some(value.to_string()),
-            None => None,
-        };
+        let icon_url: Option<String> = icon_url.map(|value| value.to_string());
 
-        let url: Option<String> = match url {
-            Some(value) => Some(value.to_string()),
-            None => None,
-        };
+        let url: Option<String> = url.map(|value| value.to_string());
 
         self.author = Some(EmbedAuthor::new(name.to_string(), url, icon_url));
 
@@ -1197,9 +1461,570 @@ impl EmbedAttachment {
 
 impl Message {
     pub fn is_bot(&self) -> bool {
-        match self.author.bot {
-            Some(value) => value,
-            None => false,
+        self.author.bot.unwrap_or_default()
+    }
+
+    pub async fn fetch(channel_id: Snowflake, message_id: Snowflake, client: bot::BotClient) -> Result<Message, discord::DiscordError> {
+        let extension = format!("/channels/{}/messages/{}", channel_id, message_id);
+        let response = discord::HttpRequest::string_new(extension, client).await.get().await?;
+
+        Ok(response.json().await?)
+    }
+
+    // only the content, embeds and files are edited, new files replace the message's attachments
+    pub async fn edit(&self, edit: &ReplyMessage, client: bot::BotClient) -> Result<Message, discord::DiscordError> {
+        // discord clears anything sent as null, so whatever wasn't set is left out
+        let mut message = serde_json::json!({});
+
+        if let Some(content) = &edit.content {
+            message["content"] = serde_json::json!(content);
+        }
+
+        if let Some(embeds) = &edit.embeds {
+            message["embeds"] = serde_json::json!(embeds);
+        }
+
+        let extension = format!("/channels/{}/messages/{}", self.channel_id, self.id);
+        let payload = discord::HttpRequest::string_new(extension, client).await;
+
+        let response = if edit.files.is_empty() {
+            payload.patch(Some(message)).await?
+        } else {
+            message["attachments"] = FileUpload::attachments(&edit.files);
+            payload.patch_multipart(message, &edit.files).await?
+        };
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn delete(&self, client: bot::BotClient) -> Result<(), discord::DiscordError> {
+        let extension = format!("/channels/{}/messages/{}", self.channel_id, self.id);
+        discord::HttpRequest::string_new(extension, client).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    // publishes a message in a news channel to every channel following it
+    pub async fn crosspost(&self, client: bot::BotClient) -> Result<Message, discord::DiscordError> {
+        let extension = format!("/channels/{}/messages/{}/crosspost", self.channel_id, self.id);
+        let response = discord::HttpRequest::string_new(extension, client)
+            .await
+            .post(serde_json::json!({}))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    // discord rejects the whole request if any message is too old, so we check before sending
+    pub async fn bulk_delete(
+        channel_id: Snowflake,
+        message_ids: Vec<Snowflake>,
+        client: bot::BotClient,
+    ) -> Result<(), discord::DiscordError> {
+        if !(2..=100).contains(&message_ids.len()) {
+            return Err(discord::DiscordError::InvalidRequest(format!(
+                "bulk delete takes 2 to 100 messages, got {}",
+                message_ids.len()
+            )));
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let oldest = now.saturating_sub(BULK_DELETE_MAX_AGE).as_millis() as u64;
+
+        for message_id in &message_ids {
+            match message_id.timestamp() {
+                Some(timestamp) if timestamp > oldest => {}
+                Some(_) => {
+                    return Err(discord::DiscordError::InvalidRequest(format!(
+                        "message {} is too old to bulk delete",
+                        message_id
+                    )))
+                }
+                None => {
+                    return Err(discord::DiscordError::InvalidRequest(format!(
+                        "{} is not a message id",
+                        message_id
+                    )))
+                }
+            }
+        }
+
+        let extension = format!("/channels/{}/messages/bulk-delete", channel_id);
+        discord::HttpRequest::string_new(extension, client)
+            .await
+            .post(serde_json::json!({ "messages": message_ids }))
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl Channel {
+    pub fn messages(&self, client: bot::BotClient) -> MessageHistory {
+        MessageHistory::new(self.id.clone(), client)
+    }
+
+    pub async fn create(
+        guild_id: Snowflake,
+        settings: &ChannelSettings,
+        reason: Option<&str>,
+        client: bot::BotClient,
+    ) -> Result<Channel, discord::DiscordError> {
+        let extension = format!("/guilds/{}/channels", guild_id);
+        let response = reasoned_request(extension, reason, client).await.post(serde_json::json!(settings)).await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn modify(
+        &self,
+        settings: &ChannelSettings,
+        reason: Option<&str>,
+        client: bot::BotClient,
+    ) -> Result<Channel, discord::DiscordError> {
+        let extension = format!("/channels/{}", self.id);
+        let response = reasoned_request(extension, reason, client)
+            .await
+            .patch(Some(serde_json::json!(settings)))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    // discord hands back the channel that was deleted
+    pub async fn delete(&self, reason: Option<&str>, client: bot::BotClient) -> Result<Channel, discord::DiscordError> {
+        let extension = format!("/channels/{}", self.id);
+        let response = reasoned_request(extension, reason, client).await.delete(None).await?;
+
+        Ok(response.json().await?)
+    }
+
+    // creates the overwrite or replaces the one already there for its role or member
+    pub async fn edit_permission(
+        &self,
+        overwrite: &PermissionOverwrite,
+        reason: Option<&str>,
+        client: bot::BotClient,
+    ) -> Result<(), discord::DiscordError> {
+        let overwrite_json = serde_json::json!({
+            "allow": overwrite.allow,
+            "deny": overwrite.deny,
+            "type": overwrite.permission_overwrite_type
+        });
+
+        let extension = format!("/channels/{}/permissions/{}", self.id, overwrite.id);
+        reasoned_request(extension, reason, client).await.put(Some(overwrite_json)).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_permission(
+        &self,
+        overwrite_id: Snowflake,
+        reason: Option<&str>,
+        client: bot::BotClient,
+    ) -> Result<(), discord::DiscordError> {
+        let extension = format!("/channels/{}/permissions/{}", self.id, overwrite_id);
+        reasoned_request(extension, reason, client).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    // shows the bot typing for 10 seconds or until it sends a message
+    pub async fn trigger_typing(&self, client: bot::BotClient) -> Result<(), discord::DiscordError> {
+        let extension = format!("/channels/{}/typing", self.id);
+        discord::HttpRequest::string_new(extension, client)
+            .await
+            .post(serde_json::json!({}))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn pins(&self, client: bot::BotClient) -> Result<Vec<Message>, discord::DiscordError> {
+        let extension = format!("/channels/{}/pins", self.id);
+        let response = discord::HttpRequest::string_new(extension, client).await.get().await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn pin(&self, message_id: Snowflake, reason: Option<&str>, client: bot::BotClient) -> Result<(), discord::DiscordError> {
+        let extension = format!("/channels/{}/pins/{}", self.id, message_id);
+        reasoned_request(extension, reason, client).await.put(None).await?;
+
+        Ok(())
+    }
+
+    pub async fn unpin(&self, message_id: Snowflake, reason: Option<&str>, client: bot::BotClient) -> Result<(), discord::DiscordError> {
+        let extension = format!("/channels/{}/pins/{}", self.id, message_id);
+        reasoned_request(extension, reason, client).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    // posts this news channel's crossposted messages into the target channel through a webhook
+    pub async fn follow(&self, target_channel_id: Snowflake, client: bot::BotClient) -> Result<FollowedChannel, discord::DiscordError> {
+        let extension = format!("/channels/{}/followers", self.id);
+        let response = discord::HttpRequest::string_new(extension, client)
+            .await
+            .post(serde_json::json!({ "webhook_channel_id": target_channel_id }))
+            .await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn create_invite(
+        &self,
+        settings: &InviteSettings,
+        reason: Option<&str>,
+        client: bot::BotClient,
+    ) -> Result<Invite, discord::DiscordError> {
+        let extension = format!("/channels/{}/invites", self.id);
+        let response = reasoned_request(extension, reason, client).await.post(serde_json::json!(settings)).await?;
+
+        Ok(response.json().await?)
+    }
+
+    pub async fn invites(&self, client: bot::BotClient) -> Result<Vec<Invite>, discord::DiscordError> {
+        let extension = format!("/channels/{}/invites", self.id);
+        let response = discord::HttpRequest::string_new(extension, client).await.get().await?;
+
+        Ok(response.json().await?)
+    }
+}
+
+// the reason shows in the guild's audit log next to the change
+async fn reasoned_request(extension: String, reason: Option<&str>, client: bot::BotClient) -> discord::HttpRequest {
+    let request = discord::HttpRequest::string_new(extension, client).await;
+
+    match reason {
+        Some(reason) => request.audit_log_reason(reason),
+        None => request,
+    }
+}
+
+impl ChannelSettings {
+    pub fn new(name: &str, channel_type: u64) -> Self {
+        Self {
+            name: Some(String::from(name)),
+            channel_type: Some(channel_type),
+            ..Default::default()
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> ChannelSettings {
+        self.name = Some(String::from(name));
+        self
+    }
+
+    pub fn topic(mut self, topic: &str) -> ChannelSettings {
+        self.topic = Some(String::from(topic));
+        self
+    }
+
+    pub fn position(mut self, position: u64) -> ChannelSettings {
+        self.position = Some(position);
+        self
+    }
+
+    // the category the channel goes under
+    pub fn parent(mut self, parent_id: Snowflake) -> ChannelSettings {
+        self.parent_id = Some(parent_id);
+        self
+    }
+
+    pub fn nsfw(mut self, nsfw: bool) -> ChannelSettings {
+        self.nsfw = Some(nsfw);
+        self
+    }
+
+    // slowmode, in seconds
+    pub fn rate_limit_per_user(mut self, seconds: u64) -> ChannelSettings {
+        self.rate_limit_per_user = Some(seconds);
+        self
+    }
+
+    pub fn bitrate(mut self, bitrate: u64) -> ChannelSettings {
+        self.bitrate = Some(bitrate);
+        self
+    }
+
+    pub fn user_limit(mut self, user_limit: u64) -> ChannelSettings {
+        self.user_limit = Some(user_limit);
+        self
+    }
+
+    pub fn add_permission_overwrite(mut self, overwrite: PermissionOverwrite) -> ChannelSettings {
+        self.permission_overwrites.get_or_insert_with(Vec::new).push(overwrite);
+        self
+    }
+}
+
+impl InviteSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // in seconds, 0 never expires
+    pub fn max_age(mut self, max_age: u64) -> InviteSettings {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    // 0 is unlimited
+    pub fn max_uses(mut self, max_uses: u64) -> InviteSettings {
+        self.max_uses = Some(max_uses);
+        self
+    }
+
+    // members who joined with it are kicked when they go offline unless they've been given a role
+    pub fn temporary(mut self, temporary: bool) -> InviteSettings {
+        self.temporary = Some(temporary);
+        self
+    }
+
+    // without this discord may hand back an existing invite with the same settings
+    pub fn unique(mut self, unique: bool) -> InviteSettings {
+        self.unique = Some(unique);
+        self
+    }
+}
+
+impl PermissionOverwrite {
+    pub fn role(role_id: Snowflake, allow: u64, deny: u64) -> Self {
+        Self {
+            id: role_id,
+            permission_overwrite_type: 0,
+            allow: allow.to_string(),
+            deny: deny.to_string(),
+        }
+    }
+
+    pub fn member(user_id: Snowflake, allow: u64, deny: u64) -> Self {
+        Self {
+            id: user_id,
+            permission_overwrite_type: 1,
+            allow: allow.to_string(),
+            deny: deny.to_string(),
+        }
+    }
+}
+
+impl MessageHistory {
+    pub fn new(channel_id: Snowflake, client: bot::BotClient) -> Self {
+        Self { channel_id, client }
+    }
+
+    // newest first, starting from the channel's last message
+    pub fn latest(self) -> BoxStream<'static, Result<Message, discord::DiscordError>> {
+        self.pages(HistoryDirection::Before, None)
+    }
+
+    // newest first, starting just before the message
+    pub fn before(self, message_id: Snowflake) -> BoxStream<'static, Result<Message, discord::DiscordError>> {
+        self.pages(HistoryDirection::Before, Some(message_id))
+    }
+
+    // oldest first, starting just after the message
+    pub fn after(self, message_id: Snowflake) -> BoxStream<'static, Result<Message, discord::DiscordError>> {
+        self.pages(HistoryDirection::After, Some(message_id))
+    }
+
+    // discord can't page on from around a message, so this is one page centred on it
+    pub fn around(self, message_id: Snowflake) -> BoxStream<'static, Result<Message, discord::DiscordError>> {
+        self.pages(HistoryDirection::Around, Some(message_id))
+    }
+
+    fn pages(self, direction: HistoryDirection, cursor: Option<Snowflake>) -> BoxStream<'static, Result<Message, discord::DiscordError>> {
+        let MessageHistory { channel_id, client } = self;
+
+        // the state is the cursor for the next page, None once we've had the last one
+        stream::try_unfold(Some(cursor), move |cursor| {
+            let extension = format!("/channels/{}/messages", channel_id);
+            let client = client.clone();
+
+            async move {
+                let cursor = match cursor {
+                    Some(cursor) => cursor,
+                    None => return Ok(None),
+                };
+
+                let mut request = discord::HttpRequest::string_new(extension, client)
+                    .await
+                    .query("limit", MESSAGE_PAGE_LIMIT);
+
+                if let Some(cursor) = cursor {
+                    request = request.query(direction.query_name(), cursor);
+                }
+
+                let mut page: Vec<Message> = request.get().await?.json().await?;
+
+                // discord sends every page newest first
+                if direction == HistoryDirection::After {
+                    page.reverse();
+                }
+
+                let next = match page.last() {
+                    Some(last) if page.len() >= MESSAGE_PAGE_LIMIT && direction != HistoryDirection::Around => {
+                        Some(Some(last.id.clone()))
+                    }
+                    _ => None,
+                };
+
+                Ok::<_, discord::DiscordError>(Some((page, next)))
+            }
+        })
+        .map_ok(|page| stream::iter(page.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+}
+
+impl Message {
+    pub fn reactions(&self, client: bot::BotClient) -> MessageReactions {
+        MessageReactions::new(self.channel_id.clone(), self.id.clone(), client)
+    }
+}
+
+impl MessageReactions {
+    pub fn new(channel_id: Snowflake, message_id: Snowflake, client: bot::BotClient) -> Self {
+        Self {
+            channel_id,
+            message_id,
+            client,
+        }
+    }
+
+    fn extension(&self, emoji: Option<&ReactionEmoji>) -> String {
+        match emoji {
+            Some(emoji) => format!("/channels/{}/messages/{}/reactions/{}", self.channel_id, self.message_id, emoji.encoded()),
+            None => format!("/channels/{}/messages/{}/reactions", self.channel_id, self.message_id),
+        }
+    }
+
+    pub async fn create(&self, emoji: impl Into<ReactionEmoji>) -> Result<(), discord::DiscordError> {
+        let extension = format!("{}/@me", self.extension(Some(&emoji.into())));
+        discord::HttpRequest::string_new(extension, self.client.clone()).await.put(None).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_own(&self, emoji: impl Into<ReactionEmoji>) -> Result<(), discord::DiscordError> {
+        let extension = format!("{}/@me", self.extension(Some(&emoji.into())));
+        discord::HttpRequest::string_new(extension, self.client.clone()).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_user(&self, emoji: impl Into<ReactionEmoji>, user_id: Snowflake) -> Result<(), discord::DiscordError> {
+        let extension = format!("{}/{}", self.extension(Some(&emoji.into())), user_id);
+        discord::HttpRequest::string_new(extension, self.client.clone()).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_all(&self) -> Result<(), discord::DiscordError> {
+        let extension = self.extension(None);
+        discord::HttpRequest::string_new(extension, self.client.clone()).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_all_for(&self, emoji: impl Into<ReactionEmoji>) -> Result<(), discord::DiscordError> {
+        let extension = self.extension(Some(&emoji.into()));
+        discord::HttpRequest::string_new(extension, self.client.clone()).await.delete(None).await?;
+
+        Ok(())
+    }
+
+    // everyone who reacted with the emoji, in order of user id
+    pub fn users(&self, emoji: impl Into<ReactionEmoji>) -> BoxStream<'static, Result<User, discord::DiscordError>> {
+        let extension = self.extension(Some(&emoji.into()));
+        let client = self.client.clone();
+
+        // the state is the user to continue after, None once we've had the last page
+        stream::try_unfold(Some(None), move |after: Option<Option<Snowflake>>| {
+            let extension = extension.clone();
+            let client = client.clone();
+
+            async move {
+                let after = match after {
+                    Some(after) => after,
+                    None => return Ok(None),
+                };
+
+                let mut request = discord::HttpRequest::string_new(extension, client)
+                    .await
+                    .query("limit", REACTION_USERS_PAGE_LIMIT);
+
+                if let Some(after) = after {
+                    request = request.query("after", after);
+                }
+
+                let page: Vec<User> = request.get().await?.json().await?;
+
+                let next = match page.last() {
+                    Some(last) if page.len() >= REACTION_USERS_PAGE_LIMIT => Some(Some(last.id.clone())),
+                    _ => None,
+                };
+
+                Ok::<_, discord::DiscordError>(Some((page, next)))
+            }
+        })
+        .map_ok(|page| stream::iter(page.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+}
+
+impl ReactionEmoji {
+    // how the emoji goes in a url, unicode percent encoded and custom emoji as name:id
+    pub fn encoded(&self) -> String {
+        let encode = |text: &str| percent_encoding::utf8_percent_encode(text, discord::URL_COMPONENT).to_string();
+
+        match self {
+            ReactionEmoji::Unicode(emoji) => encode(emoji),
+            ReactionEmoji::Custom { name, id } => format!("{}:{}", encode(name), id),
+        }
+    }
+}
+
+impl From<&str> for ReactionEmoji {
+    fn from(emoji: &str) -> Self {
+        ReactionEmoji::Unicode(String::from(emoji))
+    }
+}
+
+impl From<String> for ReactionEmoji {
+    fn from(emoji: String) -> Self {
+        ReactionEmoji::Unicode(emoji)
+    }
+}
+
+// custom emoji have an id, unicode ones only a name
+impl From<&Emoji> for ReactionEmoji {
+    fn from(emoji: &Emoji) -> Self {
+        let name = emoji.name.clone().unwrap_or_default();
+
+        match &emoji.id {
+            Some(id) => ReactionEmoji::Custom { name, id: id.clone() },
+            None => ReactionEmoji::Unicode(name),
+        }
+    }
+}
+
+impl From<Emoji> for ReactionEmoji {
+    fn from(emoji: Emoji) -> Self {
+        ReactionEmoji::from(&emoji)
+    }
+}
+
+impl HistoryDirection {
+    fn query_name(&self) -> &'static str {
+        match self {
+            HistoryDirection::Before => "before",
+            HistoryDirection::After => "after",
+            HistoryDirection::Around => "around",
         }
     }
 }
@@ -1,32 +1,36 @@
-use crate::{bot, disc_objects};
-use futures::lock::Mutex;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
+use crate::{bot, disc_objects, etf, ratelimit};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{
     connect_async, tungstenite, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
 };
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
 
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
+use futures_util::{stream::SplitStream, SinkExt, StreamExt};
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::{oneshot, watch};
 type WbSS = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 use serde_json::value::Value as SerdeValue;
-use SerdeValue::Null as SerdeNull;
-use SerdeValue::Number as SerdeNumber;
-use SerdeValue::Object as SerdeObject;
-use SerdeValue::String as SerdeString;
-use crate::disc_objects::GatewayEventBinding;
 
-type SerdeMap = serde_json::map::Map<String, SerdeValue>;
-
-pub const DISCORD_API: &'static str = "https://discord.com/api";
+pub const DISCORD_API: &str = "https://discord.com/api";
 pub const VALID_API: [u32; 3] = [7, 8, 9];
-pub const LIBRARY_NAME: &'static str = "Celestial";
+pub const LIBRARY_NAME: &str = "Celestial";
+pub const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+// discord doesn't answer gateway requests it ignores, like joining a voice channel we can't connect to
+pub const GATEWAY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// everything but unreserved characters gets percent encoded when it goes in a url or header
+pub const URL_COMPONENT: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
 
 pub static USER_AGENT: &str = concat!(
 "DiscordBot (",
@@ -40,14 +44,62 @@ pub struct Client {
     pub token: String,
     pub api_url: String,
     pub request_client: reqwest::Client,
+    pub rate_limiter: Arc<ratelimit::RateLimiter>,
     pub gateway: Gateway,
     pub api_ver: u32,
     pub heartbeat_interval: u64,
     pub intents: Intent,
     pub sequence: Option<u64>,
+    pub session_id: Option<String>,
+    pub heartbeat_acked: bool,
+    pub last_heartbeat: Option<Instant>,
+    latency: Option<Duration>,
+    pub shard: Option<[u64; 2]>,
+    pub compress: bool,
+    pub encoding: Encoding,
+    pub presence: Option<disc_objects::GatewayPresence>,
+    pub sink: Option<bot::GatewaySink>,
+    member_requests: HashMap<String, MemberRequest>,
+    pub user_id: Option<disc_objects::Snowflake>,
+    voice_requests: HashMap<String, VoiceRequest>,
+    pub shutdown: Option<watch::Receiver<bool>>,
+    // every running handler holds a read guard, so taking the write lock waits for all of them
+    pub handlers: Arc<tokio::sync::RwLock<()>>,
+    pub gateway_request_timeout: Duration,
+}
+
+// everything a voice connection needs to identify with a voice server
+#[derive(Debug, Clone)]
+pub struct VoiceSession {
+    pub guild_id: disc_objects::Snowflake,
+    pub user_id: disc_objects::Snowflake,
+    pub session_id: String,
+    pub token: String,
+    pub endpoint: String,
+}
+
+// a join voice call waiting on both halves of its voice session
+#[derive(Debug)]
+struct VoiceRequest {
+    session_id: Option<String>,
+    server: Option<disc_objects::VoiceServerUpdateEvent>,
+    sender: oneshot::Sender<VoiceSession>,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+// the chunks collected so far for one request guild members call
+#[derive(Debug)]
+struct MemberRequest {
+    members: Vec<disc_objects::GuildMember>,
+    sender: oneshot::Sender<Vec<disc_objects::GuildMember>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Etf,
+}
+
+#[derive(Debug, Serialize)]
 pub struct Payload {
     #[serde(rename = "op")]
     pub opcode: u32,
@@ -58,17 +110,65 @@ pub struct Payload {
     pub gateway_type: disc_objects::GatewayEventBinding,
 }
 
-#[derive(Deserialize, Debug)]
+// the read half of a gateway connection, with its own zlib context when transport compression is on
+pub struct GatewayReader {
+    pub stream: SplitStream<WbSS>,
+    inflater: Option<Inflater>,
+    encoding: Encoding,
+}
+
+pub struct Inflater {
+    decompress: flate2::Decompress,
+    buffer: Vec<u8>,
+}
+
+// why we stopped reading from a gateway connection
+#[derive(Debug)]
+pub enum Disconnect {
+    Closed(Option<u16>),
+    Reconnect,
+    InvalidSession(bool),
+    Zombie,
+    Shutdown,
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Gateway {
     pub url: String,
-    shards: u32,
-    session_start_limit: HashMap<String, u64>,
+    pub shards: u32,
+    pub session_start_limit: SessionStartLimit,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SessionStartLimit {
+    pub total: u64,
+    pub remaining: u64,
+    pub reset_after: u64, // milliseconds
+    pub max_concurrency: u64,
+}
+
+#[derive(Clone, Copy)]
+enum RequestBody<'a> {
+    Empty,
+    Json(&'a SerdeValue),
+    Multipart(&'a SerdeValue, &'a [disc_objects::FileUpload]),
+}
+
+impl<'a> From<Option<&'a SerdeValue>> for RequestBody<'a> {
+    fn from(content: Option<&'a SerdeValue>) -> Self {
+        match content {
+            Some(content) => RequestBody::Json(content),
+            None => RequestBody::Empty,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct HttpRequest {
     extension: String,
     client: bot::BotClient,
+    query: Vec<(String, String)>,
+    audit_log_reason: Option<String>,
 }
 
 bitflags::bitflags! {
@@ -94,13 +194,14 @@ bitflags::bitflags! {
 
 
 #[derive(Debug, Deserialize, Serialize)]
+#[allow(clippy::large_enum_variant)]
 #[serde(tag="t", content="d", rename_all(serialize = "SCREAMING_SNAKE_CASE", deserialize = "SCREAMING_SNAKE_CASE"))]
 pub enum GatewayEvent {
     Hello(disc_objects::Hello),
     Ready(disc_objects::ReadyEvent),
     Resumed,
     Reconnect,
-    InvalidSession,
+    InvalidSession(bool),
     ChannelCreate(disc_objects::Channel),
     ChannelUpdate(disc_objects::Channel),
     ChannelDelete(disc_objects::Channel),
@@ -149,12 +250,142 @@ pub enum GatewayEvent {
     VoiceStateUpdate(disc_objects::VoiceState),
     VoiceServerUpdate(disc_objects::VoiceServerUpdateEvent),
     WebhooksUpdate(disc_objects::WebhookUpdateEvent),
+
+    // dispatches with no variant above, or whose data didn't fit theirs, are left as plain json
+    #[serde(skip)]
+    Unknown { name: String, data: SerdeValue },
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum DiscordError {
     #[error("Reqwest error: {0}")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Gateway websocket error: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+    #[error("Gateway closed: {0:?}")]
+    GatewayClosed(Disconnect),
+    #[error("Failed to decode {0}")]
+    Decode(String),
+    #[error("Failed to encode {0}")]
+    Encode(String),
+    #[error("Discord API error {status} ({code}): {message}")]
+    Api {
+        status: u16,
+        code: u64,
+        message: String,
+        errors: Option<ApiErrors>,
+    },
+    #[error("Discord rejected the bot token")]
+    InvalidToken,
+    #[error("Too many invalid requests, refusing to send more for {0:?}")]
+    TooManyInvalidRequests(Duration),
+    // caught before sending, so it doesn't count towards the invalid request limit
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Discord didn't answer the {0} in time")]
+    Timeout(String),
+}
+
+#[derive(Deserialize)]
+struct RateLimited {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
+}
+
+// the json body discord sends with an unsuccessful response
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    #[serde(default)]
+    code: u64,
+    message: String,
+    errors: Option<ApiErrors>,
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize, PartialEq, Eq)]
+pub struct FieldError {
+    pub code: String,
+    pub message: String,
+}
+
+// discord's nested form errors, keyed by field name or array index down to the _errors on each bad field
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApiErrors {
+    pub errors: Vec<FieldError>,
+    pub fields: BTreeMap<String, ApiErrors>,
+}
+
+impl ApiErrors {
+    pub fn from_value(value: &SerdeValue) -> Self {
+        let mut tree = ApiErrors::default();
+
+        if let SerdeValue::Object(map) = value {
+            for (key, value) in map {
+                match key.as_str() {
+                    "_errors" => tree.errors = serde_json::from_value(value.clone()).unwrap_or_default(),
+                    _ => {
+                        tree.fields.insert(key.clone(), ApiErrors::from_value(value));
+                    }
+                }
+            }
+        }
+
+        tree
+    }
+
+    // the errors for one field, e.g. get("embeds").get("0")
+    pub fn get(&self, field: &str) -> Option<&ApiErrors> {
+        self.fields.get(field)
+    }
+
+    // every error paired with the dotted path to its field, e.g. "embeds.0.title"
+    pub fn flatten(&self) -> Vec<(String, FieldError)> {
+        let mut flattened: Vec<(String, FieldError)> =
+            self.errors.iter().map(|error| (String::new(), error.clone())).collect();
+
+        for (field, tree) in &self.fields {
+            for (path, error) in tree.flatten() {
+                let path = match path.is_empty() {
+                    true => field.clone(),
+                    false => format!("{}.{}", field, path),
+                };
+                flattened.push((path, error));
+            }
+        }
+
+        flattened
+    }
+}
+
+impl<'de> Deserialize<'de> for ApiErrors {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(ApiErrors::from_value(&SerdeValue::deserialize(deserializer)?))
+    }
+}
+
+// boxed to keep every Result carrying a DiscordError small
+impl From<tungstenite::Error> for DiscordError {
+    fn from(error: tungstenite::Error) -> Self {
+        DiscordError::WebSocket(Box::new(error))
+    }
+}
+
+impl Disconnect {
+    // close codes where discord won't accept another identify until something is changed
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Disconnect::Closed(Some(4004)) | Disconnect::Closed(Some(4010..=4014)) | Disconnect::Shutdown
+        )
+    }
+
+    pub fn is_resumable(&self) -> bool {
+        match self {
+            Disconnect::InvalidSession(resumable) => *resumable,
+            Disconnect::Closed(Some(4007)) | Disconnect::Closed(Some(4009)) => false,
+            _ => true,
+        }
+    }
 }
 
 impl Client {
@@ -169,50 +400,176 @@ impl Client {
             token: token.clone(),
             api_url,
             request_client: Client::request_client_new(token),
+            rate_limiter: Arc::new(ratelimit::RateLimiter::new()),
             gateway: Gateway::new_placeholder(),
             api_ver,
             heartbeat_interval: 0,
             intents: intents.into_iter().collect(),
             sequence: None,
+            session_id: None,
+            heartbeat_acked: true,
+            last_heartbeat: None,
+            latency: None,
+            shard: None,
+            compress: false,
+            encoding: Encoding::Json,
+            presence: None,
+            sink: None,
+            member_requests: HashMap::new(),
+            user_id: None,
+            voice_requests: HashMap::new(),
+            shutdown: None,
+            handlers: Arc::new(tokio::sync::RwLock::new(())),
+            gateway_request_timeout: GATEWAY_REQUEST_TIMEOUT,
+        }
+    }
+
+    // asks discord for zlib-stream transport compression on every gateway connection
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    // the presence every identify starts with
+    pub fn presence(mut self, presence: Option<disc_objects::GatewayPresence>) -> Self {
+        self.presence = presence;
+        self
+    }
+
+    // how long member requests and voice joins wait for discord before giving up
+    pub fn gateway_request_timeout(mut self, gateway_request_timeout: Duration) -> Self {
+        self.gateway_request_timeout = gateway_request_timeout;
+        self
+    }
+
+    // a fresh connection state for one shard, sharing everything else with this client
+    pub fn new_shard(&self, shard_id: u64, num_shards: u64) -> Self {
+        Self {
+            token: self.token.clone(),
+            api_url: self.api_url.clone(),
+            request_client: self.request_client.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            gateway: self.gateway.clone(),
+            api_ver: self.api_ver,
+            heartbeat_interval: 0,
+            intents: self.intents,
+            sequence: None,
+            session_id: None,
+            heartbeat_acked: true,
+            last_heartbeat: None,
+            latency: None,
+            shard: Some([shard_id, num_shards]),
+            compress: self.compress,
+            encoding: self.encoding,
+            presence: self.presence.clone(),
+            sink: None,
+            member_requests: HashMap::new(),
+            user_id: None,
+            voice_requests: HashMap::new(),
+            shutdown: self.shutdown.clone(),
+            handlers: self.handlers.clone(),
+            gateway_request_timeout: self.gateway_request_timeout,
         }
     }
 
+    pub fn shard_id(&self) -> u64 {
+        self.shard.map(|[shard_id, _]| shard_id).unwrap_or(0)
+    }
+
+    // round trip time between our last heartbeat and discord acknowledging it
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn heartbeat_ack(&mut self) {
+        self.heartbeat_acked = true;
+        self.latency = self.last_heartbeat.map(|sent| sent.elapsed());
+    }
+
     pub async fn heartbeat(
         client: bot::BotClient,
-        mut write_stream: &mut SplitSink<WbSS, Message>,
-    ) {
-        let heartbeat_interval = client.lock().await.heartbeat_interval.clone();
+        write_stream: &bot::GatewaySink,
+    ) -> Disconnect {
+        let heartbeat_interval = client.lock().await.heartbeat_interval;
         let first_interval = heartbeat_interval as f64 * rand::random::<f64>();
 
         sleep(Duration::from_secs_f64(first_interval / 1000.0)).await;
 
         loop {
-            let sequence = client.lock().await.sequence.clone();
+            let mut client_guard = client.lock().await;
 
-            let sequence = match sequence.is_none() {
-                true => SerdeNull,
-                false => SerdeNumber(serde_json::Number::from(sequence.unwrap())),
-            };
+            // no ack since the last heartbeat means the connection has zombied
+            if !client_guard.heartbeat_acked {
+                drop(client_guard);
 
-            Gateway::send(1, Some(sequence), None, None, &mut write_stream).await;
+                let close_frame = CloseFrame {
+                    code: CloseCode::Library(4000),
+                    reason: "Heartbeat was not acknowledged".into(),
+                };
+                let _ = write_stream.lock().await.send(Message::Close(Some(close_frame))).await;
+
+                return Disconnect::Zombie;
+            }
+
+            client_guard.heartbeat_acked = false;
+            client_guard.last_heartbeat = Some(Instant::now());
+            drop(client_guard);
+
+            if Client::send_heartbeat(client.clone(), write_stream).await.is_err() {
+                return Disconnect::Closed(None);
+            }
 
             sleep(Duration::from_millis(heartbeat_interval)).await;
         }
     }
 
-    pub async fn keep_awake() {
-        // could put connection checks, etc. in here
-        loop {
-            sleep(Duration::from_secs(30)).await;
+    // resolves once the bot is asked to shut down, never if it can't be
+    pub async fn shutdown_requested(shutdown: Option<watch::Receiver<bool>>) {
+        let mut shutdown = match shutdown {
+            Some(shutdown) => shutdown,
+            None => return futures::future::pending().await,
+        };
+
+        while !*shutdown.borrow() {
+            // nobody is left who could ask us to shut down
+            if shutdown.changed().await.is_err() {
+                return futures::future::pending().await;
+            }
         }
     }
 
-    pub async fn identify(client : bot::BotClient, mut write_stream: &mut SplitSink<WbSS, Message>) {
+    // resolves once the bot is asked to shut down, then closes the connection normally
+    pub async fn wait_for_shutdown(shutdown: Option<watch::Receiver<bool>>, write_stream: &bot::GatewaySink) -> Disconnect {
+        Client::shutdown_requested(shutdown).await;
+
+        let close_frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "Shutting down".into(),
+        };
+        let _ = write_stream.lock().await.send(Message::Close(Some(close_frame))).await;
+
+        Disconnect::Shutdown
+    }
+
+    pub async fn send_heartbeat(client: bot::BotClient, write_stream: &bot::GatewaySink) -> Result<(), DiscordError> {
+        let (sequence, encoding) = {
+            let client_guard = client.lock().await;
+            (client_guard.sequence, client_guard.encoding)
+        };
+
+        Gateway::send(1, Some(serde_json::json!(sequence)), None, None, encoding, write_stream).await
+    }
+
+    pub async fn identify(client : bot::BotClient, write_stream: &bot::GatewaySink) -> Result<(), DiscordError> {
 
-        let client = client.clone();
         let client_guard = client.lock().await;
 
-        let data = serde_json::json!({
+        let mut data = serde_json::json!({
             "token": client_guard.token,
             "intents": client_guard.intents.bits(),
             "properties": {
@@ -222,45 +579,296 @@ impl Client {
             }
         });
 
-        Gateway::send(2, Some(data), None, None, &mut write_stream).await;
+        if let Some(shard) = client_guard.shard {
+            data["shard"] = serde_json::json!(shard);
+        }
+
+        if let Some(presence) = &client_guard.presence {
+            data["presence"] = serde_json::json!(presence);
+        }
+
+        Gateway::send(2, Some(data), None, None, client_guard.encoding, write_stream).await
+    }
+
+    // changes our status and activities on this client's gateway connection, later identifies keep the new presence
+    pub async fn update_presence(
+        client: bot::BotClient,
+        status: disc_objects::Status,
+        activities: Vec<disc_objects::BotActivity>,
+        afk: bool,
+        since: Option<u64>,
+    ) -> Result<(), DiscordError> {
+        let presence = disc_objects::GatewayPresence::new(status, activities, afk, since);
+
+        let (sink, encoding) = {
+            let mut client_guard = client.lock().await;
+            client_guard.presence = Some(presence.clone());
+            (client_guard.sink.clone(), client_guard.encoding)
+        };
+
+        let sink = sink.ok_or(DiscordError::GatewayClosed(Disconnect::Closed(None)))?;
+        Gateway::send(3, Some(serde_json::json!(presence)), None, None, encoding, &sink).await
+    }
+
+    // asks discord for guild members over the gateway, resolves once every chunk sharing our nonce has arrived
+    pub async fn request_guild_members(
+        client: bot::BotClient,
+        guild_id: disc_objects::Snowflake,
+        query: disc_objects::MemberQuery,
+        limit: u64,
+        presences: bool,
+    ) -> Result<Vec<disc_objects::GuildMember>, DiscordError> {
+        let nonce: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+
+        let mut data = serde_json::json!({
+            "guild_id": guild_id,
+            "limit": limit,
+            "presences": presences,
+            "nonce": nonce,
+        });
+
+        match query {
+            disc_objects::MemberQuery::Query(query) => data["query"] = serde_json::json!(query),
+            disc_objects::MemberQuery::UserIds(user_ids) => data["user_ids"] = serde_json::json!(user_ids),
+        }
+
+        let (sender, receiver) = oneshot::channel();
+
+        let (sink, encoding, request_timeout) = {
+            let mut client_guard = client.lock().await;
+            client_guard.member_requests.insert(nonce.clone(), MemberRequest { members: Vec::new(), sender });
+            (client_guard.sink.clone(), client_guard.encoding, client_guard.gateway_request_timeout)
+        };
+
+        let sent = match sink {
+            Some(sink) => Gateway::send(8, Some(data), None, None, encoding, &sink).await,
+            None => Err(DiscordError::GatewayClosed(Disconnect::Closed(None))),
+        };
+
+        if let Err(error) = sent {
+            client.lock().await.member_requests.remove(&nonce);
+            return Err(error);
+        }
+
+        // the sender is dropped if the session ends before the last chunk
+        match timeout(request_timeout, receiver).await {
+            Ok(members) => members.map_err(|_| DiscordError::GatewayClosed(Disconnect::Closed(None))),
+            Err(_) => {
+                client.lock().await.member_requests.remove(&nonce);
+                Err(DiscordError::Timeout(String::from("guild member request")))
+            }
+        }
+    }
+
+    // adds a chunk to the request it answers, finishing the request on its last chunk
+    pub fn collect_member_chunk(&mut self, chunk: &disc_objects::GuildMembersChunkEvent) {
+        let nonce = match &chunk.nonce {
+            Some(disc_objects::Nonce::String(nonce)) => nonce.clone(),
+            Some(disc_objects::Nonce::Integer(nonce)) => nonce.to_string(),
+            None => return,
+        };
+
+        let request = match self.member_requests.get_mut(&nonce) {
+            Some(request) => request,
+            None => return,
+        };
+
+        request.members.extend(chunk.members.iter().cloned());
+
+        if chunk.chunk_index + 1 >= chunk.chunk_count {
+            if let Some(request) = self.member_requests.remove(&nonce) {
+                let _ = request.sender.send(request.members);
+            }
+        }
     }
 
+    // joins or moves to a voice channel, resolves once discord has sent both our voice state and the voice server
+    pub async fn join_voice(
+        client: bot::BotClient,
+        guild_id: disc_objects::Snowflake,
+        channel_id: disc_objects::Snowflake,
+        self_mute: bool,
+        self_deaf: bool,
+    ) -> Result<VoiceSession, DiscordError> {
+        let (sender, receiver) = oneshot::channel();
 
-    pub async fn connect(client : bot::BotClient) -> (WbSS, tungstenite::handshake::client::Response) {
-        let client = client.clone();
+        let request_timeout = {
+            let mut client_guard = client.lock().await;
+            client_guard.voice_requests.insert(
+                guild_id.to_string(),
+                VoiceRequest {
+                    session_id: None,
+                    server: None,
+                    sender,
+                },
+            );
+            client_guard.gateway_request_timeout
+        };
 
-        let connection_url = url::Url::parse(client.lock().await.gateway.url.as_str()).unwrap();
+        if let Err(error) = Client::update_voice_state(client.clone(), &guild_id, Some(&channel_id), self_mute, self_deaf).await {
+            client.lock().await.voice_requests.remove(&guild_id.to_string());
+            return Err(error);
+        }
 
-        let (websocket_stream, response) = connect_async(connection_url)
-            .await
-            .expect("Connect_async failed to connect to gateway: ");
+        // discord sends nothing back if we're missing CONNECT or the channel is full
+        match timeout(request_timeout, receiver).await {
+            Ok(session) => session.map_err(|_| DiscordError::GatewayClosed(Disconnect::Closed(None))),
+            Err(_) => {
+                client.lock().await.voice_requests.remove(&guild_id.to_string());
+                Err(DiscordError::Timeout(String::from("voice channel join")))
+            }
+        }
+    }
 
-        (websocket_stream, response)
+    pub async fn leave_voice(client: bot::BotClient, guild_id: disc_objects::Snowflake) -> Result<(), DiscordError> {
+        client.lock().await.voice_requests.remove(&guild_id.to_string());
+        Client::update_voice_state(client, &guild_id, None, false, false).await
     }
 
-    pub async fn check_hello(client : bot::BotClient, read: &mut SplitStream<WbSS>) {
+    async fn update_voice_state(
+        client: bot::BotClient,
+        guild_id: &disc_objects::Snowflake,
+        channel_id: Option<&disc_objects::Snowflake>,
+        self_mute: bool,
+        self_deaf: bool,
+    ) -> Result<(), DiscordError> {
+        let data = serde_json::json!({
+            "guild_id": guild_id,
+            "channel_id": channel_id,
+            "self_mute": self_mute,
+            "self_deaf": self_deaf,
+        });
 
-        let client = client.clone();
-        let hello_payload = Gateway::read_next_payload(read).await;
+        let (sink, encoding) = {
+            let client_guard = client.lock().await;
+            (client_guard.sink.clone(), client_guard.encoding)
+        };
 
-        let data = match hello_payload.data.unwrap() {
-            GatewayEvent::Hello(hello_message) => hello_message,
-            _ => panic!("Did not recieve hello gateway event")
+        let sink = sink.ok_or(DiscordError::GatewayClosed(Disconnect::Closed(None)))?;
+        Gateway::send(4, Some(data), None, None, encoding, &sink).await
+    }
+
+    // only our own voice state answers a join voice call
+    pub fn collect_voice_state(&mut self, voice_state: &disc_objects::VoiceState) {
+        let is_us = match &self.user_id {
+            Some(user_id) => user_id.to_string() == voice_state.user_id.to_string(),
+            None => false,
+        };
+
+        if let (true, Some(guild_id)) = (is_us, &voice_state.guild_id) {
+            if let Some(request) = self.voice_requests.get_mut(&guild_id.to_string()) {
+                request.session_id = Some(voice_state.session_id.clone());
+            }
+
+            self.finish_voice_request(guild_id.to_string());
+        }
+    }
+
+    pub fn collect_voice_server(&mut self, voice_server: &disc_objects::VoiceServerUpdateEvent) {
+        if let Some(guild_id) = &voice_server.guild_id {
+            if let Some(request) = self.voice_requests.get_mut(&guild_id.to_string()) {
+                request.server = Some(voice_server.clone());
+            }
+
+            self.finish_voice_request(guild_id.to_string());
+        }
+    }
+
+    fn finish_voice_request(&mut self, guild_id: String) {
+        // a null endpoint means the voice server went away, discord sends another update once there's a new one
+        let ready = match self.voice_requests.get(&guild_id) {
+            Some(request) => {
+                request.session_id.is_some()
+                    && matches!(&request.server, Some(server) if server.endpoint.is_some())
+            }
+            None => false,
+        };
+
+        if !ready {
+            return;
+        }
+
+        if let (Some(request), Some(user_id)) = (self.voice_requests.remove(&guild_id), self.user_id.clone()) {
+            let server = request.server.unwrap();
+
+            let _ = request.sender.send(VoiceSession {
+                guild_id: disc_objects::Snowflake::String(guild_id),
+                user_id,
+                session_id: request.session_id.unwrap(),
+                token: server.token,
+                endpoint: server.endpoint.unwrap(),
+            });
+        }
+    }
+
+    // gateway replies for a lost session never arrive, so anything still waiting on one fails instead
+    pub fn cancel_gateway_requests(&mut self) {
+        self.member_requests.clear();
+        self.voice_requests.clear();
+    }
+
+    pub async fn resume(client : bot::BotClient, write_stream: &bot::GatewaySink) -> Result<(), DiscordError> {
+
+        let client_guard = client.lock().await;
+
+        let data = serde_json::json!({
+            "token": client_guard.token,
+            "session_id": client_guard.session_id,
+            "seq": client_guard.sequence,
+        });
+
+        Gateway::send(6, Some(data), None, None, client_guard.encoding, write_stream).await
+    }
+
+    pub async fn connect(client : bot::BotClient) -> Result<(WbSS, tungstenite::handshake::client::Response), DiscordError> {
+
+        let client_guard = client.lock().await;
+
+        let mut connection_url = url::Url::parse(client_guard.gateway.url.as_str())
+            .map_err(|_| tungstenite::Error::Url(tungstenite::error::UrlError::NoHostName))?;
+
+        if client_guard.compress {
+            connection_url.query_pairs_mut().append_pair("compress", "zlib-stream");
+        }
+
+        if client_guard.encoding == Encoding::Etf {
+            connection_url.query_pairs_mut().append_pair("encoding", "etf");
+        }
+        drop(client_guard);
+
+        Ok(connect_async(connection_url).await?)
+    }
+
+    pub async fn check_hello(client : bot::BotClient, read: &mut GatewayReader) -> Result<(), DiscordError> {
+
+        let hello_payload = Gateway::read_next_payload(read).await?;
+
+        let data = match hello_payload.data {
+            Some(GatewayEvent::Hello(hello_message)) => hello_message,
+            _ => return Err(DiscordError::Decode(format!("hello, got opcode {} instead", hello_payload.opcode))),
         };
 
         let heartbeat_interval: u64 = data.heartbeat_interval;
 
-        client.lock().await.heartbeat_interval = heartbeat_interval;
+        let mut client_guard = client.lock().await;
+        client_guard.heartbeat_interval = heartbeat_interval;
+        client_guard.heartbeat_acked = true;
+        Ok(())
     }
 
     fn request_client_new(token: String) -> reqwest::Client {
         let builder = reqwest::ClientBuilder::new();
         let mut headers = reqwest::header::HeaderMap::new();
         let auth_token = format!("Bot {}", token);
-        let auth_header =
-            reqwest::header::HeaderValue::from_str(&auth_token).expect("Invalid token");
 
-        headers.insert("Authorization", auth_header);
+        // a token that can't even be a header gets a 401 from discord, which surfaces as InvalidToken
+        if let Ok(auth_header) = reqwest::header::HeaderValue::from_str(&auth_token) {
+            headers.insert("Authorization", auth_header);
+        }
 
         builder
             .user_agent(USER_AGENT)
@@ -274,54 +882,63 @@ impl Gateway {
         Self {
             url: String::new(),
             shards: 1,
-            session_start_limit: HashMap::new(),
+            session_start_limit: SessionStartLimit {
+                total: 1000,
+                remaining: 1000,
+                reset_after: 0,
+                max_concurrency: 1,
+            },
         }
     }
 
-    pub async fn opcode_conversion(check_value : String) -> String {
-
-        let check_value: serde_json::Value = serde_json::from_str(check_value.as_str()).expect("Failed to check opcode in json conversion");
+    pub async fn read_next_payload(reader: &mut GatewayReader) -> Result<Payload, DiscordError> {
 
-        let opcode = check_value["op"].as_u64().expect(format!("{}", check_value["op"]).as_str());
-
-        let returned_type = match opcode {
-            11 => SerdeString(String::from("HEARTBEAT_OK")),
-            10 => SerdeString(String::from("HELLO")),
-            9 => SerdeString(String::from("INVALID_SESSION")),
-            7 => SerdeString(String::from("RECONNECT")),
-            1 => SerdeString(String::from("HEARTBEAT")),
-            _ => check_value["t"].clone()
-        };
+        loop {
+            let data = match reader.stream.next().await {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => {
+                    let inflated = match reader.inflater.as_mut() {
+                        Some(inflater) => inflater.inflate(&data),
+                        None => Ok(Some(data)),
+                    };
 
-        let return_string = serde_json::json!({
-           "op": check_value["op"],
-            "d": check_value["d"].clone(),
-            "s": check_value["s"],
-            "t": returned_type,
-            "gateway_type": returned_type
-        });
+                    match inflated {
+                        Ok(Some(data)) => data,
+                        Ok(None) => continue,
+                        // the zlib context is unusable from here on, only a new connection can fix it
+                        Err(_) => return Err(DiscordError::GatewayClosed(Disconnect::Closed(None))),
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    return Err(DiscordError::GatewayClosed(Disconnect::Closed(frame.map(|frame| frame.code.into()))))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return Err(DiscordError::GatewayClosed(Disconnect::Closed(None))),
+            };
 
-        println!("{:?}",serde_json::from_value::<Payload>(return_string.clone()));
-        serde_json::to_string(&return_string).unwrap()
+            return Gateway::decode_payload(&data, reader.encoding);
+        }
 
     }
 
-    pub async fn read_next_payload(read_stream: &mut SplitStream<WbSS>) -> Payload {
-
-        let next_item = Gateway::opcode_conversion(
-            read_stream
-                .next()
-                .await
-                .expect("Gateway was closed when attempting to read next item")
-                .expect("Error checking to see if connected")
-                .to_string()).await;
-
-
-        serde_json::from_str(next_item.as_str()).expect(format!("Failed converting next item in string to payload {} ",next_item).as_str())
+    // events we can't model still carry a sequence number, so fall back to the header and hand over d as it came
+    pub fn decode_payload(data: &[u8], encoding: Encoding) -> Result<Payload, DiscordError> {
+        Gateway::decode::<Payload>(data, encoding).or_else(|error| {
+            Gateway::decode::<PayloadHeader>(data, encoding)
+                .ok()
+                .and_then(PayloadHeader::into_payload)
+                .ok_or_else(|| DiscordError::Decode(format!("gateway payload: {}", error)))
+        })
+    }
 
+    fn decode<T: DeserializeOwned>(data: &[u8], encoding: Encoding) -> Result<T, String> {
+        match encoding {
+            Encoding::Json => serde_json::from_slice(data).map_err(|error| error.to_string()),
+            Encoding::Etf => etf::from_slice(data).map_err(|error| error.to_string()),
+        }
     }
 
-    pub async fn send(opcode: u64, data: Option<SerdeValue>, sequence: Option<u64>, gateway_type: Option<disc_objects::GatewayEventBinding>, sink: &mut SplitSink<WbSS, Message>) {
+    pub async fn send(opcode: u64, data: Option<SerdeValue>, sequence: Option<u64>, gateway_type: Option<disc_objects::GatewayEventBinding>, encoding: Encoding, sink: &bot::GatewaySink) -> Result<(), DiscordError> {
 
         let send_payload = serde_json::json!({
             "op": opcode,
@@ -330,49 +947,451 @@ impl Gateway {
             "t" : gateway_type,
         });
 
-        let send_payload =
-            serde_json::to_string(&send_payload).expect("Failed converting payload to string for sending");
-        sink.send(Message::Text(send_payload)).await;
+        let send_payload = match encoding {
+            Encoding::Json => Message::Text(
+                serde_json::to_string(&send_payload).map_err(|error| DiscordError::Encode(error.to_string()))?,
+            ),
+            Encoding::Etf => Message::Binary(
+                etf::to_vec(&send_payload).map_err(|error| DiscordError::Encode(error.to_string()))?,
+            ),
+        };
+
+        Ok(sink.lock().await.send(send_payload).await?)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(field_identifier, rename_all = "lowercase")]
+enum PayloadField {
+    Op,
+    D,
+    S,
+    T,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct PayloadHeader {
+    op: u32,
+    s: Option<u64>,
+    t: Option<String>,
+    #[serde(default)]
+    d: Option<SerdeValue>,
+}
+
+struct PayloadVisitor;
+
+// decodes d straight into the GatewayEvent variant named by the tag
+struct EventSeed<'a> {
+    tag: &'a str,
+    unknown: bool,
+}
+
+// presents d to GatewayEvent's derived deserializer as {"t": tag, "d": d}, so nothing has to be buffered
+struct TaggedEvent<'a, D> {
+    tag: &'a str,
+    content: Option<D>,
+    fields_read: u8,
+}
+
+// the binding for a frame, along with the tag GatewayEvent knows its data by
+fn gateway_type<E: de::Error>(opcode: u32, name: Option<&str>) -> Result<(disc_objects::GatewayEventBinding, Option<&str>), E> {
+    let tag = match opcode {
+        11 => return Ok((disc_objects::GatewayEventBinding::HeartbeatOk, None)),
+        1 => return Ok((disc_objects::GatewayEventBinding::Heartbeat, None)),
+        10 => "HELLO",
+        9 => "INVALID_SESSION",
+        7 => "RECONNECT",
+        0 => name.ok_or_else(|| E::missing_field("t"))?,
+        _ => return Err(E::custom(format!("unexpected gateway opcode {}", opcode))),
+    };
+
+    let binding = disc_objects::GatewayEventBinding::deserialize(tag.into_deserializer())?;
+    Ok((binding, Some(tag)))
+}
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(PayloadVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = Payload;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a gateway payload")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Payload, A::Error> {
+        let mut opcode: Option<u32> = None;
+        let mut name: Option<String> = None;
+        let mut sequence: Option<u64> = None;
+        let mut data: Option<Option<GatewayEvent>> = None;
+        let mut buffered: Option<SerdeValue> = None;
+
+        while let Some(field) = map.next_key::<PayloadField>()? {
+            match field {
+                PayloadField::Op => opcode = Some(map.next_value()?),
+                PayloadField::S => sequence = map.next_value()?,
+                PayloadField::T => name = map.next_value()?,
+                // discord sends op and t before d, only buffer d if that ever changes
+                PayloadField::D => match opcode {
+                    Some(opcode) if opcode != 0 || name.is_some() => {
+                        data = Some(match gateway_type(opcode, name.as_deref())? {
+                            (gateway_type, Some(tag)) => Some(map.next_value_seed(EventSeed::new(&gateway_type, tag))?),
+                            (_, None) => {
+                                map.next_value::<de::IgnoredAny>()?;
+                                None
+                            }
+                        });
+                    }
+                    _ => buffered = Some(map.next_value()?),
+                },
+                PayloadField::Other => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        let opcode = opcode.ok_or_else(|| de::Error::missing_field("op"))?;
+        let (gateway_type, tag) = gateway_type(opcode, name.as_deref())?;
+
+        let data = match (data, buffered, tag) {
+            (Some(data), _, _) => data,
+            (None, Some(buffered), Some(tag)) => Some(
+                EventSeed::new(&gateway_type, tag).deserialize(buffered).map_err(de::Error::custom)?,
+            ),
+            _ => None,
+        };
+
+        Ok(Payload {
+            opcode,
+            data,
+            sequence,
+            gateway_type,
+        })
+    }
+}
+
+impl PayloadHeader {
+    fn into_payload(self) -> Option<Payload> {
+        let (gateway_type, tag) = gateway_type::<de::value::Error>(self.op, self.t.as_deref()).ok()?;
+        let data = match (tag, self.d) {
+            (Some(tag), Some(data)) => Some(GatewayEvent::Unknown { name: tag.to_string(), data }),
+            _ => None,
+        };
+
+        Some(Payload {
+            opcode: self.op,
+            data,
+            sequence: self.s,
+            gateway_type,
+        })
+    }
+}
+
+impl<'a> EventSeed<'a> {
+    fn new(gateway_type: &disc_objects::GatewayEventBinding, tag: &'a str) -> Self {
+        Self {
+            tag,
+            unknown: matches!(gateway_type, disc_objects::GatewayEventBinding::Unknown(_)),
+        }
+    }
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for EventSeed<'a> {
+    type Value = GatewayEvent;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<GatewayEvent, D::Error> {
+        if self.unknown {
+            return Ok(GatewayEvent::Unknown {
+                name: self.tag.to_string(),
+                data: SerdeValue::deserialize(deserializer)?,
+            });
+        }
+
+        GatewayEvent::deserialize(TaggedEvent {
+            tag: self.tag,
+            content: Some(deserializer),
+            fields_read: 0,
+        })
+    }
+}
+
+impl<'de, 'a, D: Deserializer<'de>> Deserializer<'de> for TaggedEvent<'a, D> {
+    type Error = D::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        visitor.visit_map(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf option
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de, 'a, D: Deserializer<'de>> MapAccess<'de> for TaggedEvent<'a, D> {
+    type Error = D::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, D::Error> {
+        let key = match self.fields_read {
+            0 => "t",
+            1 => "d",
+            _ => return Ok(None),
+        };
+
+        seed.deserialize(de::value::StrDeserializer::new(key)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, D::Error> {
+        self.fields_read += 1;
+
+        match self.content.take() {
+            Some(content) if self.fields_read == 2 => seed.deserialize(content),
+            content => {
+                self.content = content;
+                seed.deserialize(de::value::StrDeserializer::new(self.tag))
+            }
+        }
+    }
+}
+
+impl GatewayReader {
+    pub fn new(stream: SplitStream<WbSS>, compress: bool, encoding: Encoding) -> Self {
+        Self {
+            stream,
+            encoding,
+            inflater: match compress {
+                true => Some(Inflater::new()),
+                false => None,
+            },
+        }
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inflater {
+    // every zlib-stream message is flushed with this suffix, anything else is only part of a message
+    const ZLIB_SUFFIX: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+    pub fn new() -> Self {
+        Self {
+            decompress: flate2::Decompress::new(true),
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn inflate(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, flate2::DecompressError> {
+        self.buffer.extend_from_slice(data);
+
+        if !self.buffer.ends_with(&Inflater::ZLIB_SUFFIX) {
+            return Ok(None);
+        }
+
+        let mut output = Vec::with_capacity(self.buffer.len() * 4);
+        let mut offset = 0;
+
+        loop {
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let status = self.decompress.decompress_vec(&self.buffer[offset..], &mut output, flate2::FlushDecompress::Sync)?;
+
+            offset += (self.decompress.total_in() - total_in) as usize;
+            let progressed = self.decompress.total_in() != total_in || self.decompress.total_out() != total_out;
+
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity());
+                continue;
+            }
+
+            if offset >= self.buffer.len() || status == flate2::Status::StreamEnd || !progressed {
+                break;
+            }
+        }
+
+        self.buffer.clear();
+        Ok(Some(output))
     }
 }
 
 impl HttpRequest {
     pub async fn str_new(extension: &str, client: bot::BotClient) -> Self {
+        HttpRequest::string_new(String::from(extension), client).await
+    }
+
+    pub async fn string_new(extension: String, client: bot::BotClient) -> Self {
         Self {
-            extension: String::from(extension),
+            extension,
             client,
+            query: Vec::new(),
+            audit_log_reason: None,
         }
     }
 
-    pub async fn string_new(extension: String, client: bot::BotClient) -> Self {
-        Self { extension, client }
+    pub fn query<T: ToString>(mut self, key: &str, value: T) -> Self {
+        self.query.push((String::from(key), value.to_string()));
+        self
     }
 
-    pub async fn get(&self) -> Result<reqwest::Response, DiscordError> {
-        let request_url = format!("{}{}", self.client.lock().await.api_url, self.extension);
+    // shows up in the guild's audit log next to whatever this request changes
+    pub fn audit_log_reason(mut self, reason: &str) -> Self {
+        self.audit_log_reason = Some(String::from(reason));
+        self
+    }
 
-        Ok(self
-            .client
-            .lock()
-            .await
-            .request_client
-            .get(request_url)
-            .send()
-            .await?)
+    pub async fn get(&self) -> Result<reqwest::Response, DiscordError> {
+        self.send(reqwest::Method::GET, RequestBody::Empty).await
     }
 
     pub async fn post(&self, content: SerdeValue) -> Result<reqwest::Response, DiscordError> {
-        let request_url = format!("{}{}", self.client.lock().await.api_url, self.extension);
-
-        Ok(self
-            .client
-            .lock()
-            .await
-            .request_client
-            .post(request_url)
-            .json::<SerdeValue>(&content)
-            .send()
-            .await?)
+        self.send(reqwest::Method::POST, RequestBody::Json(&content)).await
+    }
+
+    pub async fn patch(&self, content: Option<SerdeValue>) -> Result<reqwest::Response, DiscordError> {
+        self.send(reqwest::Method::PATCH, RequestBody::from(content.as_ref())).await
+    }
+
+    pub async fn put(&self, content: Option<SerdeValue>) -> Result<reqwest::Response, DiscordError> {
+        self.send(reqwest::Method::PUT, RequestBody::from(content.as_ref())).await
+    }
+
+    pub async fn delete(&self, content: Option<SerdeValue>) -> Result<reqwest::Response, DiscordError> {
+        self.send(reqwest::Method::DELETE, RequestBody::from(content.as_ref())).await
+    }
+
+    // the json goes in the payload_json part and each file in a files[n] part, n being its attachment id
+    pub async fn post_multipart(
+        &self,
+        content: SerdeValue,
+        files: &[disc_objects::FileUpload],
+    ) -> Result<reqwest::Response, DiscordError> {
+        self.send(reqwest::Method::POST, RequestBody::Multipart(&content, files)).await
+    }
+
+    pub async fn patch_multipart(
+        &self,
+        content: SerdeValue,
+        files: &[disc_objects::FileUpload],
+    ) -> Result<reqwest::Response, DiscordError> {
+        self.send(reqwest::Method::PATCH, RequestBody::Multipart(&content, files)).await
+    }
+
+    // waits on the route's rate limit, and retries for as long as discord keeps answering 429
+    async fn send(&self, method: reqwest::Method, content: RequestBody<'_>) -> Result<reqwest::Response, DiscordError> {
+        // the client stays unlocked while we wait on rate limits or discord
+        let (request_client, rate_limiter, request_url) = {
+            let client_guard = self.client.lock().await;
+            (
+                client_guard.request_client.clone(),
+                client_guard.rate_limiter.clone(),
+                format!("{}{}", client_guard.api_url, self.extension),
+            )
+        };
+
+        let route = ratelimit::Route::new(&method, &self.extension);
+        let mut retries = 0;
+
+        loop {
+            rate_limiter.acquire(&route).await?;
+
+            let mut request = request_client.request(method.clone(), request_url.as_str()).query(&self.query);
+            request = match content {
+                RequestBody::Empty => request,
+                RequestBody::Json(content) => request.json::<SerdeValue>(content),
+                // a form can only be sent once, so every retry builds its own
+                RequestBody::Multipart(content, files) => request.multipart(HttpRequest::multipart_form(content, files)),
+            };
+
+            // discord wants the reason url encoded so it can carry any unicode
+            if let Some(reason) = &self.audit_log_reason {
+                let reason = percent_encoding::utf8_percent_encode(reason, URL_COMPONENT).to_string();
+                request = request.header("X-Audit-Log-Reason", reason);
+            }
+
+            let response = match request.send().await {
+                Ok(response) => response,
+                Err(error) => {
+                    rate_limiter.release(&route).await;
+                    return Err(error.into());
+                }
+            };
+            rate_limiter.update(&route, response.status(), response.headers()).await;
+
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS || retries >= MAX_RATE_LIMIT_RETRIES {
+                return HttpRequest::check_response(response).await;
+            }
+
+            let (retry_after, global) = HttpRequest::retry_after(response).await;
+
+            if global {
+                rate_limiter.pause_globally(retry_after).await;
+            }
+
+            retries += 1;
+            sleep(retry_after).await;
+        }
+    }
+
+    fn multipart_form(content: &SerdeValue, files: &[disc_objects::FileUpload]) -> reqwest::multipart::Form {
+        let mut form = reqwest::multipart::Form::new().text("payload_json", content.to_string());
+
+        for (index, file) in files.iter().enumerate() {
+            let part = reqwest::multipart::Part::bytes(file.content.clone()).file_name(file.name.clone());
+            form = form.part(format!("files[{}]", index), part);
+        }
+
+        form
+    }
+
+    // the body's retry_after is in seconds with millisecond precision, the header is only whole seconds
+    async fn retry_after(response: reqwest::Response) -> (Duration, bool) {
+        let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from);
+
+        let header_retry_after = header(reqwest::header::RETRY_AFTER.as_str()).and_then(|retry_after| retry_after.parse::<f64>().ok());
+        let header_global = header("x-ratelimit-global").as_deref() == Some("true");
+
+        match response.json::<RateLimited>().await {
+            Ok(rate_limited) => (Duration::from_secs_f64(rate_limited.retry_after), rate_limited.global || header_global),
+            Err(_) => (Duration::from_secs_f64(header_retry_after.unwrap_or(1.0)), header_global),
+        }
+    }
+
+    // only successful responses are handed back, discord answers a bad token with 401
+    async fn check_response(response: reqwest::Response) -> Result<reqwest::Response, DiscordError> {
+        let status = response.status();
+
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        if status == reqwest::StatusCode::UNAUTHORIZED {
+            return Err(DiscordError::InvalidToken);
+        }
+
+        let body = response.text().await?;
+
+        // anything that isn't discord's error json, like a proxy's error page, is kept as the message
+        Err(match serde_json::from_str::<ApiErrorBody>(&body) {
+            Ok(error) => DiscordError::Api {
+                status: status.as_u16(),
+                code: error.code,
+                message: error.message,
+                errors: error.errors,
+            },
+            Err(_) => DiscordError::Api {
+                status: status.as_u16(),
+                code: 0,
+                message: body,
+                errors: None,
+            },
+        })
     }
 }
 
@@ -0,0 +1,695 @@
+// Erlang External Term Format, just enough of it for discord's encoding=etf gateway
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize};
+use serde::Deserialize;
+use std::convert::TryInto;
+
+const FORMAT_VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+#[derive(thiserror::Error, Debug)]
+pub enum EtfError {
+    #[error("{0}")]
+    Message(String),
+    #[error("Term did not start with the ETF version byte")]
+    Version,
+    #[error("Unexpected end of term")]
+    Eof,
+    #[error("Unsupported term tag: {0}")]
+    UnsupportedTag(u8),
+    #[error("Integer does not fit in 64 bits")]
+    IntegerTooLarge,
+    #[error("Map keys must be strings")]
+    KeyMustBeString,
+}
+
+impl ser::Error for EtfError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        EtfError::Message(msg.to_string())
+    }
+}
+
+impl de::Error for EtfError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        EtfError::Message(msg.to_string())
+    }
+}
+
+pub fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, EtfError> {
+    let mut serializer = Serializer {
+        output: vec![FORMAT_VERSION],
+    };
+
+    value.serialize(&mut serializer)?;
+    Ok(serializer.output)
+}
+
+pub fn from_slice<'de, T: de::Deserialize<'de>>(input: &'de [u8]) -> Result<T, EtfError> {
+    match input.split_first() {
+        Some((&FORMAT_VERSION, rest)) => T::deserialize(&mut Deserializer { input: rest }),
+        _ => Err(EtfError::Version),
+    }
+}
+
+pub struct Serializer {
+    output: Vec<u8>,
+}
+
+// lists and maps get their length patched in once every element has been written
+pub struct Compound<'a> {
+    serializer: &'a mut Serializer,
+    start: usize,
+    length: u32,
+    is_map: bool,
+}
+
+impl Serializer {
+    fn atom(&mut self, name: &str) {
+        self.output.push(SMALL_ATOM_UTF8_EXT);
+        self.output.push(name.len() as u8);
+        self.output.extend_from_slice(name.as_bytes());
+    }
+
+    fn binary(&mut self, data: &[u8]) {
+        self.output.push(BINARY_EXT);
+        self.output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        self.output.extend_from_slice(data);
+    }
+
+    fn big(&mut self, value: u64, negative: bool) {
+        let digits: Vec<u8> = value.to_le_bytes().iter().copied().rev().skip_while(|&digit| digit == 0).collect();
+
+        self.output.push(SMALL_BIG_EXT);
+        self.output.push(digits.len() as u8);
+        self.output.push(negative as u8);
+        self.output.extend(digits.iter().rev());
+    }
+
+    fn compound(&mut self, tag: u8) -> Compound<'_> {
+        let start = self.output.len();
+        self.output.push(tag);
+        self.output.extend_from_slice(&[0; 4]);
+
+        Compound {
+            serializer: self,
+            start,
+            length: 0,
+            is_map: tag == MAP_EXT,
+        }
+    }
+
+    // enum variants with data are a single entry map of variant name to data, same as serde_json
+    fn variant_map(&mut self, variant: &str) {
+        self.output.push(MAP_EXT);
+        self.output.extend_from_slice(&1u32.to_be_bytes());
+        self.binary(variant.as_bytes());
+    }
+}
+
+impl<'a> Compound<'a> {
+    fn finish(self) -> Result<(), EtfError> {
+        let output = &mut self.serializer.output;
+
+        if self.length == 0 && !self.is_map {
+            output.truncate(self.start);
+            output.push(NIL_EXT);
+        } else {
+            output[self.start + 1..self.start + 5].copy_from_slice(&self.length.to_be_bytes());
+
+            if !self.is_map {
+                output.push(NIL_EXT);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> ser::Serializer for &'a mut Serializer {
+    type Ok = ();
+    type Error = EtfError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), EtfError> {
+        self.atom(if v { "true" } else { "false" });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), EtfError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), EtfError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), EtfError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), EtfError> {
+        if (0..=255).contains(&v) {
+            self.output.push(SMALL_INTEGER_EXT);
+            self.output.push(v as u8);
+        } else if let Ok(v) = TryInto::<i32>::try_into(v) {
+            self.output.push(INTEGER_EXT);
+            self.output.extend_from_slice(&v.to_be_bytes());
+        } else {
+            self.big(v.unsigned_abs(), v < 0);
+        }
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), EtfError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), EtfError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), EtfError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), EtfError> {
+        match TryInto::<i32>::try_into(v) {
+            Ok(v) => self.serialize_i64(v as i64),
+            Err(_) => {
+                self.big(v, false);
+                Ok(())
+            }
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), EtfError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), EtfError> {
+        self.output.push(NEW_FLOAT_EXT);
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), EtfError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), EtfError> {
+        self.binary(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), EtfError> {
+        self.binary(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), EtfError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), EtfError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), EtfError> {
+        self.atom("nil");
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), EtfError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), EtfError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), EtfError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), EtfError> {
+        self.variant_map(variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>, EtfError> {
+        Ok(self.compound(LIST_EXT))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a>, EtfError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, EtfError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, EtfError> {
+        self.variant_map(variant);
+        Ok(self.compound(LIST_EXT))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>, EtfError> {
+        Ok(self.compound(MAP_EXT))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a>, EtfError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>, EtfError> {
+        self.variant_map(variant);
+        Ok(self.compound(MAP_EXT))
+    }
+}
+
+impl<'a> ser::SerializeSeq for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EtfError> {
+        self.length += 1;
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EtfError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EtfError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleVariant for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EtfError> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeMap for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), EtfError> {
+        self.length += 1;
+        key.serialize(&mut *self.serializer)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), EtfError> {
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStruct for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), EtfError> {
+        self.length += 1;
+        self.serializer.binary(key.as_bytes());
+        value.serialize(&mut *self.serializer)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeStructVariant for Compound<'a> {
+    type Ok = ();
+    type Error = EtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), EtfError> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), EtfError> {
+        self.finish()
+    }
+}
+
+pub struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, length: usize) -> Result<&'de [u8], EtfError> {
+        if self.input.len() < length {
+            return Err(EtfError::Eof);
+        }
+
+        let (taken, rest) = self.input.split_at(length);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn peek_tag(&self) -> Result<u8, EtfError> {
+        self.input.first().copied().ok_or(EtfError::Eof)
+    }
+
+    fn u8(&mut self) -> Result<u8, EtfError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, EtfError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, EtfError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    // atoms and binaries are both strings to serde, returns None for anything else
+    fn peek_str(&self) -> Option<&'de str> {
+        let mut peek = Deserializer { input: self.input };
+
+        let data = match peek.u8().ok()? {
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let length = peek.u16().ok()? as usize;
+                peek.take(length).ok()?
+            }
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let length = peek.u8().ok()? as usize;
+                peek.take(length).ok()?
+            }
+            BINARY_EXT => {
+                let length = peek.u32().ok()? as usize;
+                peek.take(length).ok()?
+            }
+            _ => return None,
+        };
+
+        std::str::from_utf8(data).ok()
+    }
+
+    fn atom<V: Visitor<'de>>(&mut self, name: &'de [u8], visitor: V) -> Result<V::Value, EtfError> {
+        match name {
+            b"nil" => visitor.visit_unit(),
+            b"true" => visitor.visit_bool(true),
+            b"false" => visitor.visit_bool(false),
+            _ => match std::str::from_utf8(name) {
+                Ok(name) => visitor.visit_borrowed_str(name),
+                Err(_) => visitor.visit_borrowed_bytes(name),
+            },
+        }
+    }
+
+    fn big<V: Visitor<'de>>(&mut self, length: usize, visitor: V) -> Result<V::Value, EtfError> {
+        let negative = self.u8()? != 0;
+        let digits = self.take(length)?;
+
+        if digits.iter().skip(8).any(|&digit| digit != 0) {
+            return Err(EtfError::IntegerTooLarge);
+        }
+
+        let value = digits.iter().take(8).rev().fold(0u64, |value, &digit| (value << 8) | digit as u64);
+
+        match negative {
+            true => match TryInto::<i64>::try_into(value) {
+                Ok(value) => visitor.visit_i64(-value),
+                Err(_) => Err(EtfError::IntegerTooLarge),
+            },
+            false => visitor.visit_u64(value),
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = EtfError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, EtfError> {
+        match self.u8()? {
+            SMALL_INTEGER_EXT => visitor.visit_u64(self.u8()? as u64),
+            INTEGER_EXT => visitor.visit_i64(self.u32()? as i32 as i64),
+            NEW_FLOAT_EXT => visitor.visit_f64(f64::from_be_bytes(self.take(8)?.try_into().unwrap())),
+            FLOAT_EXT => {
+                let text = std::str::from_utf8(self.take(31)?).map_err(de::Error::custom)?;
+                visitor.visit_f64(text.trim_end_matches('\0').trim().parse().map_err(de::Error::custom)?)
+            }
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let length = self.u16()? as usize;
+                let name = self.take(length)?;
+                self.atom(name, visitor)
+            }
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let length = self.u8()? as usize;
+                let name = self.take(length)?;
+                self.atom(name, visitor)
+            }
+            SMALL_TUPLE_EXT => {
+                let length = self.u8()? as usize;
+                visitor.visit_seq(Elements { de: self, remaining: length })
+            }
+            LARGE_TUPLE_EXT => {
+                let length = self.u32()? as usize;
+                visitor.visit_seq(Elements { de: self, remaining: length })
+            }
+            NIL_EXT => visitor.visit_seq(Elements { de: self, remaining: 0 }),
+            // erlang strings are lists of bytes
+            STRING_EXT => {
+                let length = self.u16()? as usize;
+                let bytes = self.take(length)?;
+                visitor.visit_seq(de::value::SeqDeserializer::new(bytes.iter().copied()))
+            }
+            LIST_EXT => {
+                let length = self.u32()? as usize;
+                let value = visitor.visit_seq(Elements { de: &mut *self, remaining: length })?;
+
+                // proper lists end with an empty list as their tail
+                de::IgnoredAny::deserialize(&mut *self)?;
+                Ok(value)
+            }
+            BINARY_EXT => {
+                let length = self.u32()? as usize;
+                let data = self.take(length)?;
+
+                match std::str::from_utf8(data) {
+                    Ok(text) => visitor.visit_borrowed_str(text),
+                    Err(_) => visitor.visit_borrowed_bytes(data),
+                }
+            }
+            SMALL_BIG_EXT => {
+                let length = self.u8()? as usize;
+                self.big(length, visitor)
+            }
+            LARGE_BIG_EXT => {
+                let length = self.u32()? as usize;
+                self.big(length, visitor)
+            }
+            MAP_EXT => {
+                let length = self.u32()? as usize;
+                visitor.visit_map(Elements { de: self, remaining: length })
+            }
+            tag => Err(EtfError::UnsupportedTag(tag)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, EtfError> {
+        match self.peek_str() {
+            Some("nil") if self.peek_tag()? != BINARY_EXT => {
+                de::IgnoredAny::deserialize(&mut *self)?;
+                visitor.visit_none()
+            }
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, EtfError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, EtfError> {
+        if let Some(variant) = self.peek_str() {
+            de::IgnoredAny::deserialize(&mut *self)?;
+            return visitor.visit_enum(variant.into_deserializer());
+        }
+
+        match self.u8()? {
+            MAP_EXT if self.u32()? == 1 => visitor.visit_enum(VariantAccess { de: self }),
+            _ => Err(de::Error::custom("expected a variant name or a single entry map")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, EtfError> {
+        self.deserialize_any(de::IgnoredAny)?;
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct Elements<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    remaining: usize,
+}
+
+impl<'a, 'de> SeqAccess<'de> for Elements<'a, 'de> {
+    type Error = EtfError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, EtfError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'a, 'de> MapAccess<'de> for Elements<'a, 'de> {
+    type Error = EtfError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, EtfError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+
+        self.remaining -= 1;
+
+        match self.de.peek_str() {
+            Some(key) => {
+                de::IgnoredAny::deserialize(&mut *self.de)?;
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Err(EtfError::KeyMustBeString),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, EtfError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+struct VariantAccess<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for VariantAccess<'a, 'de> {
+    type Error = EtfError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), EtfError> {
+        match self.de.peek_str() {
+            Some(variant) => {
+                de::IgnoredAny::deserialize(&mut *self.de)?;
+                let variant = seed.deserialize(variant.into_deserializer())?;
+                Ok((variant, self))
+            }
+            None => Err(EtfError::KeyMustBeString),
+        }
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for VariantAccess<'a, 'de> {
+    type Error = EtfError;
+
+    fn unit_variant(self) -> Result<(), EtfError> {
+        de::Deserialize::deserialize(&mut *self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, EtfError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, EtfError> {
+        de::Deserializer::deserialize_any(&mut *self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, EtfError> {
+        de::Deserializer::deserialize_any(&mut *self.de, visitor)
+    }
+}
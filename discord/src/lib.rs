@@ -1,6 +1,11 @@
 pub mod discord;
 pub mod disc_objects;
 pub mod bot;
+pub mod shard;
+pub mod etf;
 pub mod interactions;
+pub mod voice;
+pub mod rtp;
+pub mod ratelimit;
 
 
@@ -0,0 +1,283 @@
+use crate::discord::DiscordError;
+use futures::lock::Mutex;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+// top level resources whose id gets its own rate limit, everything else under a route shares one
+const MAJOR_PARAMETERS: [&str; 3] = ["channels", "guilds", "webhooks"];
+
+// discord bans an ip for an hour after 10,000 401/403/429 responses in 10 minutes, we stop well before that
+pub const INVALID_REQUEST_WINDOW: Duration = Duration::from_secs(10 * 60);
+pub const INVALID_REQUEST_THRESHOLD: u64 = 9_000;
+
+// a request that never reports back, like one whose future was dropped, only holds its bucket up this long
+const HEADERS_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<RateLimitState>,
+    // woken whenever a response tells us more about a bucket
+    updated: Notify,
+    invalid_request_threshold: u64,
+}
+
+#[derive(Debug, Default)]
+struct RateLimitState {
+    // discord only tells us which bucket a route is in once we've used it
+    buckets_by_route: HashMap<String, String>,
+    buckets: HashMap<String, Bucket>,
+    // routes discord didn't send bucket headers for
+    unlimited_routes: HashSet<String>,
+    // a global 429 stops every route until it's over
+    global_reset_at: Option<Instant>,
+    invalid_requests: u64,
+    invalid_window_start: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    limit: u64,
+    remaining: u64,
+    // None once the bucket has reset until a response tells us when the new window ends
+    reset_at: Option<Instant>,
+}
+
+// a route with its major parameter kept and every other id replaced, e.g. "GET /channels/1/messages/:id"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub route: String,
+    pub major: String,
+}
+
+impl Route {
+    pub fn new(method: &Method, path: &str) -> Self {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+        let mut route = Vec::with_capacity(segments.len());
+        let mut major = String::new();
+
+        for (index, segment) in segments.iter().enumerate() {
+            let previous = index.checked_sub(1).map(|index| segments[index]);
+
+            let segment = match previous {
+                // the first id after a major resource is the major parameter, webhook tokens count too
+                Some(resource) if index == 1 && MAJOR_PARAMETERS.contains(&resource) => {
+                    major = format!("{}/{}", resource, segment);
+                    segment.to_string()
+                }
+                Some(_) if index == 2 && segments[0] == "webhooks" => {
+                    major = format!("{}/{}", major, segment);
+                    segment.to_string()
+                }
+                // every emoji shares the reaction limits
+                Some("reactions") => String::from(":reaction"),
+                _ if segment.chars().all(|character| character.is_ascii_digit()) => String::from(":id"),
+                _ => segment.to_string(),
+            };
+
+            route.push(segment);
+        }
+
+        Self {
+            route: format!("{} /{}", method, route.join("/")),
+            major,
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(RateLimitState::default()),
+            updated: Notify::new(),
+            invalid_request_threshold: INVALID_REQUEST_THRESHOLD,
+        }
+    }
+
+    // how many invalid requests in a window we allow before refusing to send any more
+    pub fn invalid_request_threshold(mut self, invalid_request_threshold: u64) -> Self {
+        self.invalid_request_threshold = invalid_request_threshold;
+        self
+    }
+
+    // waits until the route's bucket has a request left, then uses it up
+    pub async fn acquire(&self, route: &Route) -> Result<(), DiscordError> {
+        loop {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+
+            if let Some(window_start) = state.invalid_window_start {
+                let window_end = window_start + INVALID_REQUEST_WINDOW;
+
+                if now >= window_end {
+                    state.invalid_requests = 0;
+                    state.invalid_window_start = None;
+                } else if state.invalid_requests >= self.invalid_request_threshold {
+                    return Err(DiscordError::TooManyInvalidRequests(window_end - now));
+                }
+            }
+
+            if let Some(global_reset_at) = state.global_reset_at {
+                if now < global_reset_at {
+                    drop(state);
+
+                    sleep(global_reset_at - now).await;
+                    continue;
+                }
+
+                state.global_reset_at = None;
+            }
+
+            if state.unlimited_routes.contains(&route.route) {
+                return Ok(());
+            }
+
+            // until a route's first response tells us its bucket, it gets a bucket of its own that lets one request through
+            let bucket_key = RateLimitState::bucket_key(&state, route);
+            let bucket = state.buckets.entry(bucket_key).or_insert(Bucket {
+                limit: 1,
+                remaining: 1,
+                reset_at: None,
+            });
+
+            if let Some(reset_at) = bucket.reset_at {
+                if now >= reset_at {
+                    bucket.remaining = bucket.limit;
+                    bucket.reset_at = None;
+                }
+            }
+
+            if bucket.remaining > 0 {
+                bucket.remaining -= 1;
+                return Ok(());
+            }
+
+            match bucket.reset_at {
+                Some(reset_at) => {
+                    drop(state);
+                    sleep(reset_at - now).await;
+                }
+                None => {
+                    // register before unlocking so an update in between isn't missed
+                    let updated = self.updated.notified();
+                    tokio::pin!(updated);
+                    updated.as_mut().enable();
+                    drop(state);
+
+                    if timeout(HEADERS_TIMEOUT, updated).await.is_err() {
+                        self.release(route).await;
+                    }
+                }
+            }
+        }
+    }
+
+    // hands back a request that never got a response, so the next one waiting on the bucket can go
+    pub async fn release(&self, route: &Route) {
+        let mut state = self.state.lock().await;
+        let bucket_key = RateLimitState::bucket_key(&state, route);
+
+        if let Some(bucket) = state.buckets.get_mut(&bucket_key) {
+            if bucket.reset_at.is_none() {
+                bucket.remaining = (bucket.remaining + 1).min(bucket.limit);
+            }
+        }
+
+        drop(state);
+        self.updated.notify_waiters();
+    }
+
+    // holds back every route, not just this one
+    pub async fn pause_globally(&self, retry_after: Duration) {
+        self.state.lock().await.global_reset_at = Some(Instant::now() + retry_after);
+    }
+
+    pub async fn invalid_requests(&self) -> u64 {
+        self.state.lock().await.invalid_requests
+    }
+
+    // discord's headers are the truth, they replace whatever we guessed the bucket had left
+    pub async fn update(&self, route: &Route, status: StatusCode, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        // 429s from a limit shared with other bots aren't our fault, so discord doesn't count them
+        let invalid = matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+            || (status == StatusCode::TOO_MANY_REQUESTS && header("x-ratelimit-scope") != Some("shared"));
+
+        if invalid {
+            let mut state = self.state.lock().await;
+            state.invalid_window_start.get_or_insert_with(Instant::now);
+            state.invalid_requests += 1;
+        }
+
+        let mut state = self.state.lock().await;
+        // the bucket this route had while we didn't know its real one
+        let route_key = RateLimitState::route_key(route);
+
+        let bucket = match header("x-ratelimit-bucket") {
+            Some(bucket) => bucket.to_string(),
+            None => {
+                state.buckets.remove(&route_key);
+
+                // errors from in front of discord, like a 502, don't come with headers either
+                if status.is_success() && !state.buckets_by_route.contains_key(&route.route) {
+                    state.unlimited_routes.insert(route.route.clone());
+                }
+
+                drop(state);
+                self.updated.notify_waiters();
+                return;
+            }
+        };
+
+        let limit = header("x-ratelimit-limit").and_then(|limit| limit.parse::<u64>().ok());
+        let remaining = header("x-ratelimit-remaining").and_then(|remaining| remaining.parse::<u64>().ok());
+        let reset_after = header("x-ratelimit-reset-after").and_then(|reset_after| reset_after.parse::<f64>().ok());
+
+        state.buckets.remove(&route_key);
+        state.buckets_by_route.insert(route.route.clone(), bucket.clone());
+
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            let bucket_key = format!("{}:{}", bucket, route.major);
+            // without a limit header we only know one more request is safe once the window resets
+            let limit = limit
+                .or_else(|| state.buckets.get(&bucket_key).map(|bucket| bucket.limit))
+                .unwrap_or(1);
+
+            state.buckets.insert(
+                bucket_key,
+                Bucket {
+                    limit,
+                    remaining,
+                    reset_at: Some(Instant::now() + Duration::from_secs_f64(reset_after)),
+                },
+            );
+        }
+
+        drop(state);
+        self.updated.notify_waiters();
+    }
+}
+
+impl RateLimitState {
+    fn route_key(route: &Route) -> String {
+        format!("{}:{}", route.route, route.major)
+    }
+
+    fn bucket_key(&self, route: &Route) -> String {
+        match self.buckets_by_route.get(&route.route) {
+            Some(bucket) => format!("{}:{}", bucket, route.major),
+            None => RateLimitState::route_key(route),
+        }
+    }
+}
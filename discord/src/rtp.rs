@@ -0,0 +1,236 @@
+use crate::voice::VoiceError;
+use crypto_secretbox::aead::{Aead, KeyInit};
+use crypto_secretbox::{Key, Nonce, XSalsa20Poly1305};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+pub const RTP_HEADER_LENGTH: usize = 12;
+pub const RTP_VERSION: u8 = 0x80;
+pub const OPUS_PAYLOAD_TYPE: u8 = 0x78;
+
+// one 20ms opus frame at 48khz
+pub const FRAME_SAMPLES: u32 = 960;
+
+const NONCE_LENGTH: usize = 24;
+const LITE_NONCE_LENGTH: usize = 4;
+
+#[derive(Clone, Copy, Deserialize, Debug, Serialize, PartialEq, Eq)]
+pub enum EncryptionMode {
+    // the rtp header padded out to 24 bytes is the nonce
+    #[serde(rename = "xsalsa20_poly1305")]
+    Normal,
+    // a random 24 byte nonce goes after the encrypted audio
+    #[serde(rename = "xsalsa20_poly1305_suffix")]
+    Suffix,
+    // a 4 byte counter goes after the encrypted audio, padded out to 24 bytes for the nonce
+    #[serde(rename = "xsalsa20_poly1305_lite")]
+    Lite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RtpHeader {
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct RtpPacket {
+    pub header: RtpHeader,
+    pub payload: Vec<u8>,
+}
+
+// frames and encrypts opus for the voice udp socket, keeping track of sequence and timestamp
+pub struct RtpSender {
+    udp: Arc<UdpSocket>,
+    cipher: XSalsa20Poly1305,
+    pub mode: EncryptionMode,
+    pub ssrc: u32,
+    pub sequence: u16,
+    pub timestamp: u32,
+    lite_nonce: u32,
+}
+
+pub struct RtpReceiver {
+    udp: Arc<UdpSocket>,
+    cipher: XSalsa20Poly1305,
+    pub mode: EncryptionMode,
+}
+
+impl EncryptionMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EncryptionMode::Normal => "xsalsa20_poly1305",
+            EncryptionMode::Suffix => "xsalsa20_poly1305_suffix",
+            EncryptionMode::Lite => "xsalsa20_poly1305_lite",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "xsalsa20_poly1305" => Some(EncryptionMode::Normal),
+            "xsalsa20_poly1305_suffix" => Some(EncryptionMode::Suffix),
+            "xsalsa20_poly1305_lite" => Some(EncryptionMode::Lite),
+            _ => None,
+        }
+    }
+}
+
+impl RtpHeader {
+    pub fn to_bytes(&self) -> [u8; RTP_HEADER_LENGTH] {
+        let mut header = [0u8; RTP_HEADER_LENGTH];
+        header[0] = RTP_VERSION;
+        header[1] = OPUS_PAYLOAD_TYPE;
+        header[2..4].copy_from_slice(&self.sequence.to_be_bytes());
+        header[4..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        header[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+        header
+    }
+
+    // None for anything that isn't rtp version 2, like the rtcp reports discord also sends
+    pub fn from_bytes(packet: &[u8]) -> Option<Self> {
+        if packet.len() < RTP_HEADER_LENGTH || packet[0] >> 6 != 2 || (200..=204).contains(&packet[1]) {
+            return None;
+        }
+
+        Some(Self {
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        })
+    }
+}
+
+impl RtpSender {
+    pub fn new(udp: Arc<UdpSocket>, ssrc: u32, mode: EncryptionMode, secret_key: &[u8; 32]) -> Self {
+        Self {
+            udp,
+            cipher: XSalsa20Poly1305::new(&Key::from(*secret_key)),
+            mode,
+            ssrc,
+            sequence: rand::random(),
+            timestamp: rand::random(),
+            lite_nonce: rand::random(),
+        }
+    }
+
+    // builds the next packet for an opus frame and moves sequence and timestamp on
+    pub fn encrypt(&mut self, opus_frame: &[u8]) -> Result<Vec<u8>, VoiceError> {
+        let header = RtpHeader {
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+            ssrc: self.ssrc,
+        }
+        .to_bytes();
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        let suffix = match self.mode {
+            EncryptionMode::Normal => {
+                nonce[..RTP_HEADER_LENGTH].copy_from_slice(&header);
+                Vec::new()
+            }
+            EncryptionMode::Suffix => {
+                nonce = rand::random();
+                nonce.to_vec()
+            }
+            EncryptionMode::Lite => {
+                nonce[..LITE_NONCE_LENGTH].copy_from_slice(&self.lite_nonce.to_be_bytes());
+                self.lite_nonce = self.lite_nonce.wrapping_add(1);
+                nonce[..LITE_NONCE_LENGTH].to_vec()
+            }
+        };
+
+        let encrypted = self
+            .cipher
+            .encrypt(&Nonce::from(nonce), opus_frame)
+            .map_err(|_| VoiceError::Encryption)?;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(FRAME_SAMPLES);
+
+        let mut packet = Vec::with_capacity(RTP_HEADER_LENGTH + encrypted.len() + suffix.len());
+        packet.extend_from_slice(&header);
+        packet.extend_from_slice(&encrypted);
+        packet.extend_from_slice(&suffix);
+        Ok(packet)
+    }
+
+    pub async fn send(&mut self, opus_frame: &[u8]) -> Result<(), VoiceError> {
+        let packet = self.encrypt(opus_frame)?;
+        self.udp.send(&packet).await?;
+        Ok(())
+    }
+}
+
+impl RtpReceiver {
+    pub fn new(udp: Arc<UdpSocket>, mode: EncryptionMode, secret_key: &[u8; 32]) -> Self {
+        Self {
+            udp,
+            cipher: XSalsa20Poly1305::new(&Key::from(*secret_key)),
+            mode,
+        }
+    }
+
+    // None if the packet isn't rtp at all
+    pub fn decrypt(&self, packet: &[u8]) -> Result<Option<RtpPacket>, VoiceError> {
+        let header = match RtpHeader::from_bytes(packet) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        // any csrcs sit between the fixed header and the encrypted audio
+        let header_length = RTP_HEADER_LENGTH + (packet[0] & 0x0f) as usize * 4;
+        if packet.len() < header_length {
+            return Err(VoiceError::Encryption);
+        }
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        let encrypted = match self.mode {
+            EncryptionMode::Normal => {
+                nonce[..RTP_HEADER_LENGTH].copy_from_slice(&packet[..RTP_HEADER_LENGTH]);
+                &packet[header_length..]
+            }
+            EncryptionMode::Suffix => {
+                let nonce_start = packet.len().checked_sub(NONCE_LENGTH).filter(|start| *start >= header_length);
+                let nonce_start = nonce_start.ok_or(VoiceError::Encryption)?;
+
+                nonce.copy_from_slice(&packet[nonce_start..]);
+                &packet[header_length..nonce_start]
+            }
+            EncryptionMode::Lite => {
+                let nonce_start = packet.len().checked_sub(LITE_NONCE_LENGTH).filter(|start| *start >= header_length);
+                let nonce_start = nonce_start.ok_or(VoiceError::Encryption)?;
+
+                nonce[..LITE_NONCE_LENGTH].copy_from_slice(&packet[nonce_start..]);
+                &packet[header_length..nonce_start]
+            }
+        };
+
+        let mut payload = self
+            .cipher
+            .decrypt(&Nonce::from(nonce), encrypted)
+            .map_err(|_| VoiceError::Encryption)?;
+
+        // discord encrypts the header extension along with the audio, its length is in the first 4 bytes
+        if packet[0] & 0x10 != 0 && payload.len() >= 4 {
+            let extension_length = 4 + u16::from_be_bytes([payload[2], payload[3]]) as usize * 4;
+            payload.drain(..extension_length.min(payload.len()));
+        }
+
+        Ok(Some(RtpPacket { header, payload }))
+    }
+
+    // waits for the next rtp packet, skipping anything else on the socket
+    pub async fn recv(&self) -> Result<RtpPacket, VoiceError> {
+        let mut buffer = [0u8; 2048];
+
+        loop {
+            let received = self.udp.recv(&mut buffer).await?;
+
+            if let Some(packet) = self.decrypt(&buffer[..received])? {
+                return Ok(packet);
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use crate::bot::{Bot, BotClient, EventMap};
+use crate::disc_objects::Snowflake;
+use crate::discord::{DiscordError, SessionStartLimit};
+use futures::lock::Mutex;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{sleep, Duration, Instant};
+
+// each rate limit bucket may only identify once every 5 seconds
+const IDENTIFY_INTERVAL: Duration = Duration::from_secs(5);
+const SESSION_LIMIT_RESET: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct ShardManager {
+    pub shards: Vec<BotClient>,
+    pub identify_queue: Arc<IdentifyQueue>,
+    // every shard watches this, so one shard stopping for good can stop the rest
+    pub shutdown: Arc<watch::Sender<bool>>,
+    gateway_event_map: EventMap,
+}
+
+#[derive(Debug)]
+pub struct IdentifyQueue {
+    state: Mutex<IdentifyState>,
+}
+
+#[derive(Debug)]
+struct IdentifyState {
+    total: u64,
+    remaining: u64,
+    reset_at: Instant,
+    max_concurrency: u64,
+    last_identify: HashMap<u64, Instant>,
+}
+
+impl ShardManager {
+    // every shard gets its own client, handlers can tell which shard an event came from with Client::shard_id
+    pub async fn new(client: BotClient, gateway_event_map: EventMap) -> Self {
+        let mut client_guard = client.lock().await;
+
+        let (shutdown, receiver) = watch::channel(false);
+        client_guard.shutdown = Some(receiver);
+
+        let num_shards = client_guard.gateway.shards.max(1) as u64;
+        let identify_queue = IdentifyQueue::new(&client_guard.gateway.session_start_limit);
+
+        let shards = (0..num_shards)
+            .map(|shard_id| Arc::new(Mutex::new(client_guard.new_shard(shard_id, num_shards))))
+            .collect();
+
+        Self {
+            shards,
+            identify_queue: Arc::new(identify_queue),
+            shutdown: Arc::new(shutdown),
+            gateway_event_map,
+        }
+    }
+
+    // discord sends a guild's events over shard (guild_id >> 22) % num_shards, and wants its requests there too
+    pub fn shard_id_for_guild(guild_id: &Snowflake, num_shards: u64) -> u64 {
+        guild_id.as_u64().map(|id| (id >> 22) % num_shards.max(1)).unwrap_or(0)
+    }
+
+    // every shard is started straight away, the identify queue decides when each one may identify
+    pub async fn run(&self) -> Result<(), DiscordError> {
+        let mut running: FuturesUnordered<_> = self
+            .shards
+            .iter()
+            .map(|shard| {
+                tokio::spawn(Bot::run_gateway(
+                    shard.clone(),
+                    self.gateway_event_map.clone(),
+                    self.identify_queue.clone(),
+                ))
+            })
+            .collect();
+
+        // the first shard discord closed for good explains why we stopped, the others are closed normally
+        let mut result = Ok(());
+        while let Some(stopped) = running.next().await {
+            match stopped {
+                Ok(Err(error)) if result.is_ok() => {
+                    let _ = self.shutdown.send(true);
+                    result = Err(error);
+                }
+                Ok(_) => {}
+                Err(error) => std::panic::resume_unwind(error.into_panic()),
+            }
+        }
+
+        result
+    }
+}
+
+impl IdentifyQueue {
+    pub fn new(session_start_limit: &SessionStartLimit) -> Self {
+        let state = IdentifyState {
+            total: session_start_limit.total,
+            remaining: session_start_limit.remaining,
+            reset_at: Instant::now() + Duration::from_millis(session_start_limit.reset_after),
+            max_concurrency: session_start_limit.max_concurrency.max(1),
+            last_identify: HashMap::new(),
+        };
+
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    // waits until the shard is allowed to identify, then uses up one of the session starts
+    pub async fn wait(&self, shard_id: u64) {
+        loop {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+
+            if now >= state.reset_at {
+                state.remaining = state.total;
+                state.reset_at = now + SESSION_LIMIT_RESET;
+            }
+
+            if state.remaining == 0 {
+                let reset_at = state.reset_at;
+                drop(state);
+
+                sleep(reset_at - now).await;
+                continue;
+            }
+
+            let bucket = shard_id % state.max_concurrency;
+
+            if let Some(last_identify) = state.last_identify.get(&bucket) {
+                let allowed_at = *last_identify + IDENTIFY_INTERVAL;
+
+                if now < allowed_at {
+                    drop(state);
+
+                    sleep(allowed_at - now).await;
+                    continue;
+                }
+            }
+
+            state.remaining -= 1;
+            state.last_identify.insert(bucket, now);
+            return;
+        }
+    }
+
+    pub async fn remaining(&self) -> u64 {
+        self.state.lock().await.remaining
+    }
+}
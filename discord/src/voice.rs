@@ -0,0 +1,443 @@
+use crate::discord::VoiceSession;
+use crate::rtp::{EncryptionMode, RtpReceiver, RtpSender};
+use futures::lock::Mutex;
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::Value as SerdeValue;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_tungstenite::{
+    connect_async, tungstenite, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
+};
+
+type VoiceSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+pub type VoiceSink = Arc<Mutex<SplitSink<VoiceSocket, Message>>>;
+
+pub const VOICE_GATEWAY_VERSION: u32 = 4;
+
+// best first, lite and suffix don't need the rtp header to build their nonce
+pub const SUPPORTED_MODES: [EncryptionMode; 3] = [EncryptionMode::Lite, EncryptionMode::Suffix, EncryptionMode::Normal];
+
+const DISCOVERY_LENGTH: usize = 74;
+
+// discovery is a single udp packet each way, so we ask again a few times before giving up
+const DISCOVERY_ATTEMPTS: u32 = 5;
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(thiserror::Error, Debug)]
+pub enum VoiceError {
+    #[error("Voice websocket error: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+    #[error("Voice udp error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to decode voice payload: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("Voice gateway closed with {0:?}")]
+    Closed(Option<u16>),
+    #[error("Voice server offered none of our encryption modes: {0:?}")]
+    NoSupportedMode(Vec<String>),
+    #[error("Malformed ip discovery response")]
+    Discovery,
+    #[error("Session description did not carry a 32 byte secret key")]
+    SecretKey,
+    #[error("Failed to encrypt or decrypt a voice packet")]
+    Encryption,
+}
+
+// boxed so the errors from encrypting every voice packet stay small
+impl From<tungstenite::Error> for VoiceError {
+    fn from(error: tungstenite::Error) -> Self {
+        VoiceError::WebSocket(Box::new(error))
+    }
+}
+
+impl VoiceError {
+    // 4015 means the voice server crashed, anything else discord closes with needs a whole new voice session
+    pub fn is_resumable(&self) -> bool {
+        match self {
+            VoiceError::Closed(Some(code)) => !(4001..=4016).contains(code) || *code == 4015,
+            VoiceError::Closed(None) | VoiceError::WebSocket(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct VoicePayload {
+    pub op: u64,
+    pub d: SerdeValue,
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct VoiceHello {
+    pub heartbeat_interval: f64,
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct VoiceReady {
+    pub ssrc: u32,
+    pub ip: String,
+    pub port: u16,
+    pub modes: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, Serialize)]
+pub struct SessionDescription {
+    pub mode: EncryptionMode,
+    pub secret_key: Vec<u8>,
+}
+
+// heartbeat bookkeeping and the current write half, shared with the task keeping the connection alive
+#[derive(Debug)]
+struct VoiceHeartbeat {
+    sink: VoiceSink,
+    nonce: Option<u64>,
+    last_heartbeat: Option<Instant>,
+    latency: Option<Duration>,
+}
+
+// a voice websocket that finished its handshake, with the udp socket audio goes over
+pub struct VoiceConnection {
+    pub session: VoiceSession,
+    pub ssrc: u32,
+    pub udp: Arc<UdpSocket>,
+    pub server_address: SocketAddr,
+    pub external_address: SocketAddr,
+    pub mode: EncryptionMode,
+    pub secret_key: [u8; 32],
+    heartbeat: Arc<Mutex<VoiceHeartbeat>>,
+    task: JoinHandle<VoiceError>,
+}
+
+impl VoiceConnection {
+    // identifies with the voice server, finds our external address and waits for the key to encrypt audio with
+    pub async fn connect(session: VoiceSession) -> Result<Self, VoiceError> {
+        let (write, mut read) = VoiceConnection::open(&session).await?;
+        let heartbeat_interval = VoiceConnection::read_hello(&mut read).await?;
+
+        let heartbeat = Arc::new(Mutex::new(VoiceHeartbeat {
+            sink: write.clone(),
+            nonce: None,
+            last_heartbeat: None,
+            latency: None,
+        }));
+
+        // the server expects heartbeats from hello on, however long the rest of the handshake takes
+        let handshake_heartbeat = tokio::spawn(VoiceConnection::heartbeat(heartbeat.clone(), heartbeat_interval));
+        let handshake = VoiceConnection::handshake(&session, &write, &mut read, &heartbeat).await;
+        handshake_heartbeat.abort();
+
+        let (ready, udp, server_address, external_address, description) = handshake?;
+        let secret_key: [u8; 32] = description.secret_key.try_into().map_err(|_| VoiceError::SecretKey)?;
+
+        // run sends its own heartbeat straight away, one still waiting for an ack from the handshake doesn't count
+        heartbeat.lock().await.nonce = None;
+        let task = tokio::spawn(VoiceConnection::run(session.clone(), read, heartbeat.clone(), heartbeat_interval));
+
+        Ok(Self {
+            session,
+            ssrc: ready.ssrc,
+            udp: Arc::new(udp),
+            server_address,
+            external_address,
+            mode: description.mode,
+            secret_key,
+            heartbeat,
+            task,
+        })
+    }
+
+    async fn handshake(
+        session: &VoiceSession,
+        write: &VoiceSink,
+        read: &mut SplitStream<VoiceSocket>,
+        heartbeat: &Arc<Mutex<VoiceHeartbeat>>,
+    ) -> Result<(VoiceReady, UdpSocket, SocketAddr, SocketAddr, SessionDescription), VoiceError> {
+        let identify = serde_json::json!({
+            "server_id": session.guild_id,
+            "user_id": session.user_id,
+            "session_id": session.session_id,
+            "token": session.token,
+        });
+        VoiceConnection::send(write, 0, identify).await?;
+
+        let ready: VoiceReady = VoiceConnection::read_handshake_op(read, heartbeat, 2).await?;
+
+        let mode = SUPPORTED_MODES
+            .iter()
+            .find(|mode| ready.modes.iter().any(|offered| offered == mode.name()))
+            .ok_or_else(|| VoiceError::NoSupportedMode(ready.modes.clone()))?;
+
+        let server_address: SocketAddr = tokio::net::lookup_host((ready.ip.as_str(), ready.port))
+            .await?
+            .next()
+            .ok_or(VoiceError::Discovery)?;
+
+        let udp = UdpSocket::bind(match server_address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })
+        .await?;
+        udp.connect(server_address).await?;
+
+        let external_address = discover_ip(&udp, ready.ssrc).await?;
+
+        let select_protocol = serde_json::json!({
+            "protocol": "udp",
+            "data": {
+                "address": external_address.ip().to_string(),
+                "port": external_address.port(),
+                "mode": mode,
+            }
+        });
+        VoiceConnection::send(write, 1, select_protocol).await?;
+
+        let description: SessionDescription = VoiceConnection::read_handshake_op(read, heartbeat, 4).await?;
+        Ok((ready, udp, server_address, external_address, description))
+    }
+
+    // round trip time between our last voice heartbeat and its ack
+    pub async fn latency(&self) -> Option<Duration> {
+        self.heartbeat.lock().await.latency
+    }
+
+    // discord wants to know we're speaking before it forwards any of our audio
+    pub async fn speaking(&self, speaking: bool) -> Result<(), VoiceError> {
+        let data = serde_json::json!({
+            "speaking": speaking as u8,
+            "delay": 0,
+            "ssrc": self.ssrc,
+        });
+
+        self.send_payload(5, data).await
+    }
+
+    pub fn rtp_sender(&self) -> RtpSender {
+        RtpSender::new(self.udp.clone(), self.ssrc, self.mode, &self.secret_key)
+    }
+
+    pub fn rtp_receiver(&self) -> RtpReceiver {
+        RtpReceiver::new(self.udp.clone(), self.mode, &self.secret_key)
+    }
+
+    // sends a payload on whichever websocket is current, reconnects swap it out underneath us
+    pub async fn send_payload(&self, opcode: u64, data: SerdeValue) -> Result<(), VoiceError> {
+        let sink = self.heartbeat.lock().await.sink.clone();
+        VoiceConnection::send(&sink, opcode, data).await
+    }
+
+    // the connection gave up, either on its own or because discord ended the voice session
+    pub fn is_closed(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    pub async fn disconnect(self) {
+        self.task.abort();
+        let sink = self.heartbeat.lock().await.sink.clone();
+        let _ = sink.lock().await.send(Message::Close(None)).await;
+    }
+
+    async fn open(session: &VoiceSession) -> Result<(VoiceSink, SplitStream<VoiceSocket>), VoiceError> {
+        // discord hands out bare hosts, anything with a scheme is used as is so local servers work too
+        let endpoint = match session.endpoint.contains("://") {
+            true => session.endpoint.clone(),
+            false => format!("wss://{}", session.endpoint),
+        };
+
+        let mut url = url::Url::parse(endpoint.as_str())
+            .map_err(|_| tungstenite::Error::Url(tungstenite::error::UrlError::NoHostName))?;
+        url.query_pairs_mut().append_pair("v", VOICE_GATEWAY_VERSION.to_string().as_str());
+
+        let (socket, _) = connect_async(url).await?;
+        let (write, read) = socket.split();
+
+        Ok((Arc::new(Mutex::new(write)), read))
+    }
+
+    async fn send(sink: &VoiceSink, opcode: u64, data: SerdeValue) -> Result<(), VoiceError> {
+        let payload = serde_json::to_string(&VoicePayload { op: opcode, d: data })?;
+        sink.lock().await.send(Message::Text(payload)).await?;
+        Ok(())
+    }
+
+    async fn read_payload(read: &mut SplitStream<VoiceSocket>) -> Result<VoicePayload, VoiceError> {
+        loop {
+            match read.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(text.as_str())?),
+                Some(Ok(Message::Close(frame))) => {
+                    return Err(VoiceError::Closed(frame.map(|frame| frame.code.into())))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(_)) | None => return Err(VoiceError::Closed(None)),
+            }
+        }
+    }
+
+    // skips anything else the server sends before the payload we're waiting for
+    async fn read_op<T: serde::de::DeserializeOwned>(read: &mut SplitStream<VoiceSocket>, opcode: u64) -> Result<T, VoiceError> {
+        loop {
+            let payload = VoiceConnection::read_payload(read).await?;
+
+            if payload.op == opcode {
+                return Ok(serde_json::from_value(payload.d)?);
+            }
+        }
+    }
+
+    // like read_op, but keeps track of acks for the heartbeats sent while we wait
+    async fn read_handshake_op<T: serde::de::DeserializeOwned>(
+        read: &mut SplitStream<VoiceSocket>,
+        heartbeat: &Arc<Mutex<VoiceHeartbeat>>,
+        opcode: u64,
+    ) -> Result<T, VoiceError> {
+        loop {
+            let payload = VoiceConnection::read_payload(read).await?;
+
+            if payload.op == opcode {
+                return Ok(serde_json::from_value(payload.d)?);
+            }
+
+            VoiceConnection::acknowledge(heartbeat, &payload).await;
+        }
+    }
+
+    async fn read_hello(read: &mut SplitStream<VoiceSocket>) -> Result<Duration, VoiceError> {
+        let hello: VoiceHello = VoiceConnection::read_op(read, 8).await?;
+        Ok(Duration::from_secs_f64(hello.heartbeat_interval / 1000.0))
+    }
+
+    // keeps the voice websocket alive, resuming it whenever the server drops us
+    async fn run(
+        session: VoiceSession,
+        mut read: SplitStream<VoiceSocket>,
+        heartbeat: Arc<Mutex<VoiceHeartbeat>>,
+        mut heartbeat_interval: Duration,
+    ) -> VoiceError {
+        loop {
+            let error = tokio::select! {
+                error = VoiceConnection::read(&mut read, heartbeat.clone()) => error,
+                error = VoiceConnection::heartbeat(heartbeat.clone(), heartbeat_interval) => error,
+            };
+
+            if !error.is_resumable() {
+                return error;
+            }
+
+            match VoiceConnection::resume(&session).await {
+                Ok((write, resumed_read, interval)) => {
+                    let mut heartbeat_guard = heartbeat.lock().await;
+                    heartbeat_guard.sink = write;
+                    heartbeat_guard.nonce = None;
+
+                    read = resumed_read;
+                    heartbeat_interval = interval;
+                }
+                Err(error) => return error,
+            }
+        }
+    }
+
+    async fn resume(session: &VoiceSession) -> Result<(VoiceSink, SplitStream<VoiceSocket>, Duration), VoiceError> {
+        let (write, mut read) = VoiceConnection::open(session).await?;
+        let heartbeat_interval = VoiceConnection::read_hello(&mut read).await?;
+
+        let resume = serde_json::json!({
+            "server_id": session.guild_id,
+            "session_id": session.session_id,
+            "token": session.token,
+        });
+        VoiceConnection::send(&write, 7, resume).await?;
+
+        VoiceConnection::read_op::<SerdeValue>(&mut read, 9).await?;
+        Ok((write, read, heartbeat_interval))
+    }
+
+    async fn read(read: &mut SplitStream<VoiceSocket>, heartbeat: Arc<Mutex<VoiceHeartbeat>>) -> VoiceError {
+        loop {
+            let payload = match VoiceConnection::read_payload(read).await {
+                Ok(payload) => payload,
+                Err(error) => return error,
+            };
+
+            VoiceConnection::acknowledge(&heartbeat, &payload).await;
+        }
+    }
+
+    async fn acknowledge(heartbeat: &Arc<Mutex<VoiceHeartbeat>>, payload: &VoicePayload) {
+        if payload.op != 6 {
+            return;
+        }
+
+        let mut heartbeat_guard = heartbeat.lock().await;
+
+        if heartbeat_guard.nonce.is_some() && payload.d.as_u64() == heartbeat_guard.nonce {
+            heartbeat_guard.nonce = None;
+            heartbeat_guard.latency = heartbeat_guard.last_heartbeat.map(|sent| sent.elapsed());
+        }
+    }
+
+    async fn heartbeat(heartbeat: Arc<Mutex<VoiceHeartbeat>>, heartbeat_interval: Duration) -> VoiceError {
+        loop {
+            let mut heartbeat_guard = heartbeat.lock().await;
+
+            // the last nonce was never acked, so the connection has zombied
+            if heartbeat_guard.nonce.is_some() {
+                return VoiceError::Closed(None);
+            }
+
+            let nonce: u64 = rand::random::<u32>() as u64;
+            heartbeat_guard.nonce = Some(nonce);
+            heartbeat_guard.last_heartbeat = Some(Instant::now());
+            let sink = heartbeat_guard.sink.clone();
+            drop(heartbeat_guard);
+
+            if let Err(error) = VoiceConnection::send(&sink, 3, serde_json::json!(nonce)).await {
+                return error;
+            }
+
+            sleep(heartbeat_interval).await;
+        }
+    }
+}
+
+// asks the voice server which address and port our udp socket is seen from
+pub async fn discover_ip(udp: &UdpSocket, ssrc: u32) -> Result<SocketAddr, VoiceError> {
+    let mut request = [0u8; DISCOVERY_LENGTH];
+    request[0..2].copy_from_slice(&1u16.to_be_bytes());
+    request[2..4].copy_from_slice(&70u16.to_be_bytes());
+    request[4..8].copy_from_slice(&ssrc.to_be_bytes());
+
+    let mut response = [0u8; DISCOVERY_LENGTH];
+    let mut received = None;
+
+    for _ in 0..DISCOVERY_ATTEMPTS {
+        udp.send(&request).await?;
+
+        if let Ok(answer) = timeout(DISCOVERY_TIMEOUT, udp.recv(&mut response)).await {
+            received = Some(answer?);
+            break;
+        }
+    }
+
+    let received = received.ok_or(VoiceError::Discovery)?;
+
+    if received != DISCOVERY_LENGTH || response[0..2] != 2u16.to_be_bytes() {
+        return Err(VoiceError::Discovery);
+    }
+
+    // the address is a null terminated string, the port sits in the last two bytes
+    let address = &response[8..72];
+    let address_end = address.iter().position(|byte| *byte == 0).unwrap_or(address.len());
+    let address = std::str::from_utf8(&address[..address_end]).map_err(|_| VoiceError::Discovery)?;
+    let port = u16::from_be_bytes([response[72], response[73]]);
+
+    let ip = address.parse().map_err(|_| VoiceError::Discovery)?;
+    Ok(SocketAddr::new(ip, port))
+}
@@ -8,9 +8,8 @@ use celestialcord::disc_objects;
 use celestialcord::bot::{Bot};
 use celestialcord::discord::Intent;
 use celestial_macros::add_fn;
-use tokio::time::{Instant};
 
-async fn on_ready(returned: discord::GatewayEvent, client: bot::BotClient) {
+async fn on_ready(_returned: discord::GatewayEvent, _client: bot::BotClient) {
     println!("Bot ready!");
 }
 
@@ -18,23 +17,23 @@ async fn response(message : disc_objects::Message, client: bot::BotClient) {
     let embed = disc_objects::Embed::new("Hello", "Snootiermoon!", 0xFF0000)
         .image("https://c.tenor.com/zDUT9yR2Zz0AAAAC/big-buger-eat-buger.gif");
 
-    let mut reply = disc_objects::ReplyMessage::new(false)
+    let reply = disc_objects::ReplyMessage::new(false)
         .add_embed(embed)
         .reply_message(message.clone());
 
-    let response = reply.send(message.channel_id.clone(), client.clone()).await;
+    let _response = reply.send(message.channel_id.clone(), client.clone()).await;
 
 }
 
 async fn longtask(message : disc_objects::Message, client: bot::BotClient) {
-    let mut reply = disc_objects::ReplyMessage::new(false)
+    let _reply = disc_objects::ReplyMessage::new(false)
         .content_str("Task started")
         .reply_message(message.clone())
         .send(message.channel_id.clone(), client.clone()).await;
 
     tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
 
-    let mut reply = disc_objects::ReplyMessage::new(false)
+    let _reply = disc_objects::ReplyMessage::new(false)
         .content_str("Task finished")
         .reply_message(message.clone())
         .send(message.channel_id.clone(), client.clone()).await;
@@ -69,10 +68,11 @@ async fn on_message(returned: discord::GatewayEvent, client: bot::BotClient) {
 #[tokio::test]
 async fn main() {
     // make bot
-    let mut bot = Bot::new(
+    let bot = Bot::new(
         9,
         std::env::var("BOT_TOKEN").expect("Put bot token in env_var!"),
         vec![Intent::all()],
+        None,
     );
 
 
@@ -81,5 +81,5 @@ async fn main() {
     bot.add_event(disc_objects::GatewayEventBinding::Ready, add_fn!(on_ready)).await;
 
     //alive
-    bot.elevate().await;
+    bot.elevate().await.unwrap().wait().await.unwrap();
 }
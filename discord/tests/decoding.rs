@@ -0,0 +1,195 @@
+extern crate celestialcord;
+
+use celestialcord::disc_objects::GatewayEventBinding;
+use celestialcord::discord::{DiscordError, Encoding, Gateway, GatewayEvent};
+use serde::Deserialize;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 5000;
+
+// the payload shape from before single pass decoding, gateway_type had to be injected into the json first
+#[derive(Deserialize, Debug)]
+struct OldPayload {
+    #[serde(rename = "op")]
+    _opcode: u32,
+    #[serde(flatten)]
+    data: Option<GatewayEvent>,
+    #[serde(rename = "s")]
+    sequence: Option<u64>,
+    gateway_type: GatewayEventBinding,
+}
+
+fn old_opcode_conversion(check_value: String) -> String {
+    let check_value: serde_json::Value = serde_json::from_str(check_value.as_str()).unwrap();
+
+    let returned_type = match check_value["op"].as_u64().unwrap() {
+        11 => serde_json::Value::String(String::from("HEARTBEAT_OK")),
+        10 => serde_json::Value::String(String::from("HELLO")),
+        9 => serde_json::Value::String(String::from("INVALID_SESSION")),
+        7 => serde_json::Value::String(String::from("RECONNECT")),
+        1 => serde_json::Value::String(String::from("HEARTBEAT")),
+        _ => check_value["t"].clone(),
+    };
+
+    let return_string = serde_json::json!({
+        "op": check_value["op"],
+        "d": check_value["d"].clone(),
+        "s": check_value["s"],
+        "t": returned_type,
+        "gateway_type": returned_type
+    });
+
+    serde_json::to_string(&return_string).unwrap()
+}
+
+fn old_decode(frame: &str) -> OldPayload {
+    serde_json::from_str(old_opcode_conversion(frame.to_string()).as_str()).unwrap()
+}
+
+fn message_create() -> String {
+    let embeds: Vec<serde_json::Value> = (0..5)
+        .map(|index| {
+            serde_json::json!({
+                "title": format!("Embed {}", index),
+                "type": "rich",
+                "description": "Lorem ipsum dolor sit amet, consectetur adipiscing elit",
+                "color": 16711680,
+                "fields": [
+                    {"name": "first", "value": "one", "inline": true},
+                    {"name": "second", "value": "two", "inline": false}
+                ]
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "t": "MESSAGE_CREATE",
+        "s": 42,
+        "op": 0,
+        "d": {
+            "id": "334385199974967042",
+            "channel_id": "290926798999357250",
+            "guild_id": "290926798999357250",
+            "author": {"id": "53908099506183680", "username": "Mason", "discriminator": "9999", "avatar": null},
+            "content": "Supa Hot",
+            "timestamp": "2017-07-11T17:27:07.299000+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": embeds,
+            "pinned": false,
+            "type": 0
+        }
+    })
+    .to_string()
+}
+
+#[test]
+fn decodes_dispatches_in_one_pass() {
+    let frame = message_create();
+    let payload = Gateway::decode_payload(frame.as_bytes(), Encoding::Json).unwrap();
+
+    assert_eq!(payload.sequence, Some(42));
+    assert_eq!(payload.gateway_type, GatewayEventBinding::MessageCreate);
+    assert!(matches!(payload.data, Some(GatewayEvent::MessageCreate(ref message)) if message.content == "Supa Hot"));
+
+    let old_payload = old_decode(&frame);
+    assert_eq!(payload.gateway_type, old_payload.gateway_type);
+    assert_eq!(format!("{:?}", payload.data), format!("{:?}", old_payload.data));
+}
+
+#[test]
+fn decodes_data_sent_before_its_tag() {
+    let frame = r#"{"d": {"heartbeat_interval": 41250}, "op": 10, "s": null, "t": null}"#;
+    let payload = Gateway::decode_payload(frame.as_bytes(), Encoding::Json).unwrap();
+
+    assert_eq!(payload.gateway_type, GatewayEventBinding::Hello);
+    assert!(matches!(payload.data, Some(GatewayEvent::Hello(ref hello)) if hello.heartbeat_interval == 41250));
+}
+
+#[test]
+fn keeps_the_sequence_of_events_that_fail_to_decode() {
+    let frame = r#"{"t": "TYPING_START", "s": 7, "op": 0, "d": {"channel_id": "1", "user_id": "2", "timestamp": 3}}"#;
+    let payload = Gateway::decode_payload(frame.as_bytes(), Encoding::Json).unwrap();
+
+    assert_eq!(payload.sequence, Some(7));
+    assert_eq!(payload.gateway_type, GatewayEventBinding::TypingStart);
+
+    // the data still reaches the handler, just undecoded
+    match payload.data {
+        Some(GatewayEvent::Unknown { name, data }) => {
+            assert_eq!(name, "TYPING_START");
+            assert_eq!(data["timestamp"], 3);
+        }
+        data => panic!("Expected an unknown event, got {:?}", data),
+    }
+}
+
+#[test]
+fn reports_why_a_payload_failed_to_decode() {
+    let frame = r#"{"t": "MESSAGE_CREATE", "s": "seven", "op": 0, "d": {}}"#;
+
+    match Gateway::decode_payload(frame.as_bytes(), Encoding::Json) {
+        Err(DiscordError::Decode(error)) => {
+            assert!(error.contains("invalid type"), "{}", error);
+            assert!(!error.contains("MESSAGE_CREATE"), "{}", error);
+        }
+        payload => panic!("Expected a decode error, got {:?}", payload.map(|payload| payload.sequence)),
+    }
+}
+
+#[test]
+fn decodes_unmodelled_dispatches_by_name() {
+    let frame = r#"{"t": "GUILD_SCHEDULED_EVENT_CREATE", "s": 3, "op": 0, "d": {"id": "1", "name": "Movie night"}}"#;
+    let payload = Gateway::decode_payload(frame.as_bytes(), Encoding::Json).unwrap();
+
+    assert_eq!(payload.gateway_type, GatewayEventBinding::Unknown(String::from("GUILD_SCHEDULED_EVENT_CREATE")));
+
+    match payload.data {
+        Some(GatewayEvent::Unknown { name, data }) => {
+            assert_eq!(name, "GUILD_SCHEDULED_EVENT_CREATE");
+            assert_eq!(data["name"], "Movie night");
+        }
+        data => panic!("Expected an unknown event, got {:?}", data),
+    }
+}
+
+#[test]
+fn binds_unknown_events_by_name() {
+    let binding: GatewayEventBinding = serde_json::from_str(r#""AUTO_MODERATION_ACTION_EXECUTION""#).unwrap();
+    assert_eq!(binding, GatewayEventBinding::Unknown(String::from("AUTO_MODERATION_ACTION_EXECUTION")));
+    assert_eq!(serde_json::to_string(&binding).unwrap(), r#""AUTO_MODERATION_ACTION_EXECUTION""#);
+
+    let binding: GatewayEventBinding = serde_json::from_str(r#""MESSAGE_CREATE""#).unwrap();
+    assert_eq!(binding, GatewayEventBinding::MessageCreate);
+}
+
+// cargo test --release --test decoding -- --ignored --nocapture to see the timings
+#[test]
+#[ignore]
+fn benchmark_against_old_path() {
+    let frame = message_create();
+
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(old_decode(&frame).sequence, Some(42));
+    }
+    let old_path = started.elapsed();
+
+    let started = Instant::now();
+    for _ in 0..ITERATIONS {
+        assert_eq!(Gateway::decode_payload(frame.as_bytes(), Encoding::Json).unwrap().sequence, Some(42));
+    }
+    let single_pass = started.elapsed();
+
+    println!(
+        "{} MESSAGE_CREATE frames: old path {:?}, single pass {:?} ({:.2}x)",
+        ITERATIONS,
+        old_path,
+        single_pass,
+        old_path.as_secs_f64() / single_pass.as_secs_f64()
+    );
+}
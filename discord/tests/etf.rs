@@ -0,0 +1,78 @@
+extern crate celestialcord;
+
+use celestialcord::disc_objects::Snowflake;
+use celestialcord::discord::GatewayEvent;
+use celestialcord::etf;
+
+fn small_atom(name: &str) -> Vec<u8> {
+    let mut term = vec![115, name.len() as u8];
+    term.extend_from_slice(name.as_bytes());
+    term
+}
+
+#[test]
+fn round_trips_json_values() {
+    let value = serde_json::json!({
+        "op": 2,
+        "d": {
+            "token": "token",
+            "intents": 32767,
+            "large_threshold": 250,
+            "shard": [0, 1],
+            "presence": {"since": null, "afk": false, "activities": []},
+            "id": 80351110224678912u64,
+            "offset": -2147483649i64,
+            "ratio": 0.5,
+        },
+    });
+
+    let encoded = etf::to_vec(&value).unwrap();
+    assert_eq!(encoded[0], 131);
+
+    let decoded: serde_json::Value = etf::from_slice(&encoded).unwrap();
+    assert_eq!(decoded, value);
+}
+
+#[test]
+fn decodes_discord_style_terms() {
+    // {op => 10, d => #{heartbeat_interval => 41250}, s => nil, t => nil} with atom keys, the way discord sends it
+    let mut term = vec![131, 116, 0, 0, 0, 4];
+    term.extend(small_atom("op"));
+    term.extend([97, 10]);
+    term.extend(small_atom("d"));
+    term.extend([116, 0, 0, 0, 1]);
+    term.extend(small_atom("heartbeat_interval"));
+    term.extend([98, 0, 0, 0xa1, 0x22]);
+    term.extend(small_atom("s"));
+    term.extend(small_atom("nil"));
+    term.extend(small_atom("t"));
+    term.extend(small_atom("nil"));
+
+    let decoded: serde_json::Value = etf::from_slice(&term).unwrap();
+    assert_eq!(decoded, serde_json::json!({"op": 10, "d": {"heartbeat_interval": 41250}, "s": null, "t": null}));
+}
+
+#[test]
+fn decodes_gateway_events() {
+    let mut term = vec![131, 116, 0, 0, 0, 2];
+    term.extend(small_atom("t"));
+    term.extend(small_atom("HELLO"));
+    term.extend(small_atom("d"));
+    term.extend([116, 0, 0, 0, 1]);
+    term.extend(small_atom("heartbeat_interval"));
+    term.extend([98, 0, 0, 0xa1, 0x22]);
+
+    match etf::from_slice::<GatewayEvent>(&term).unwrap() {
+        GatewayEvent::Hello(hello) => assert_eq!(hello.heartbeat_interval, 41250),
+        event => panic!("Decoded the wrong event: {:?}", event),
+    }
+}
+
+#[test]
+fn decodes_big_integers_as_snowflakes() {
+    let snowflake = Snowflake::Integer(80351110224678912);
+    let encoded = etf::to_vec(&snowflake).unwrap();
+
+    assert_eq!(encoded[1], 110);
+    assert_eq!(etf::from_slice::<Snowflake>(&encoded).unwrap(), snowflake);
+}
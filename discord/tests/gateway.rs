@@ -0,0 +1,736 @@
+extern crate celestialcord;
+
+use celestialcord::bot::Bot;
+use celestialcord::disc_objects::{BotActivity, GatewayEventBinding, GatewayPresence, MemberQuery, Snowflake, Status};
+use celestialcord::discord::{Client, DiscordError, Encoding, Intent};
+use celestialcord::etf;
+use celestialcord::shard::{IdentifyQueue, ShardManager};
+use std::sync::Arc;
+use futures::future::FutureExt;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
+use tokio_tungstenite::{accept_async, accept_hdr_async, WebSocketStream};
+
+type MockSocket = WebSocketStream<TcpStream>;
+
+async fn mock_bot(listener: &TcpListener) -> Bot {
+    mock_bot_from(listener, Bot::new(9, String::from("token"), vec![Intent::GUILDS], None)).await
+}
+
+async fn mock_bot_from(listener: &TcpListener, bot: Bot) -> Bot {
+    bot.client.lock().await.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let client = bot.client.clone();
+    let gateway_event_map = bot.gateway_event_map.clone();
+    let identify_queue = Arc::new(IdentifyQueue::new(&client.lock().await.gateway.session_start_limit));
+    tokio::spawn(async move {
+        let _ = Bot::run_gateway(client, gateway_event_map, identify_queue).await;
+    });
+
+    bot
+}
+
+async fn accept(listener: &TcpListener) -> MockSocket {
+    accept_with_interval(listener, 45000).await
+}
+
+async fn accept_with_interval(listener: &TcpListener, heartbeat_interval: u64) -> MockSocket {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut socket = accept_async(stream).await.unwrap();
+
+    send(&mut socket, serde_json::json!({"op": 10, "d": {"heartbeat_interval": heartbeat_interval}, "s": null, "t": null})).await;
+    socket
+}
+
+async fn send(socket: &mut MockSocket, payload: serde_json::Value) {
+    socket.send(Message::Text(payload.to_string())).await.unwrap();
+}
+
+// skips heartbeats, returns the next payload the client sent
+async fn receive(socket: &mut MockSocket) -> serde_json::Value {
+    loop {
+        let message = timeout(Duration::from_secs(10), socket.next())
+            .await
+            .expect("Client did not send anything")
+            .unwrap()
+            .unwrap();
+
+        let payload: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        if payload["op"] != 1 {
+            return payload;
+        }
+    }
+}
+
+fn ready_payload(session_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "op": 0,
+        "s": 1,
+        "t": "READY",
+        "d": {
+            "v": 9,
+            "user": {"id": "80351110224678912", "username": "celestial", "discriminator": "0001"},
+            "guilds": [],
+            "session_id": session_id,
+            "application": {"id": "80351110224678912"}
+        }
+    })
+}
+
+#[tokio::test]
+async fn resumes_after_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut first = accept(&listener).await;
+    assert_eq!(receive(&mut first).await["op"], 2);
+
+    send(&mut first, ready_payload("session")).await;
+    send(&mut first, serde_json::json!({"op": 7, "d": null, "s": null, "t": null})).await;
+
+    let mut second = accept(&listener).await;
+    let resume = receive(&mut second).await;
+
+    assert_eq!(resume["op"], 6);
+    assert_eq!(resume["d"]["session_id"], "session");
+    assert_eq!(resume["d"]["seq"], 1);
+    assert_eq!(bot.client.lock().await.session_id.as_deref(), Some("session"));
+}
+
+#[tokio::test]
+async fn identifies_after_invalid_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut first = accept(&listener).await;
+    assert_eq!(receive(&mut first).await["op"], 2);
+
+    send(&mut first, ready_payload("session")).await;
+    send(&mut first, serde_json::json!({"op": 9, "d": false, "s": null, "t": null})).await;
+
+    let mut second = accept(&listener).await;
+    assert_eq!(receive(&mut second).await["op"], 2);
+    assert!(bot.client.lock().await.session_id.is_none());
+}
+
+#[tokio::test]
+async fn reports_latency_from_heartbeat_acks() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut socket = accept_with_interval(&listener, 100).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    for _ in 0..2 {
+        let heartbeat = timeout(Duration::from_secs(10), socket.next()).await.unwrap().unwrap().unwrap();
+        let heartbeat: serde_json::Value = serde_json::from_str(heartbeat.to_text().unwrap()).unwrap();
+
+        assert_eq!(heartbeat["op"], 1);
+        send(&mut socket, serde_json::json!({"op": 11, "d": null, "s": null, "t": null})).await;
+    }
+
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert!(bot.client.lock().await.latency().is_some());
+}
+
+#[tokio::test]
+async fn reconnects_zombied_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let _bot = mock_bot(&listener).await;
+
+    let mut first = accept_with_interval(&listener, 100).await;
+    assert_eq!(receive(&mut first).await["op"], 2);
+    send(&mut first, ready_payload("session")).await;
+
+    // never acknowledge heartbeats, the client should give up on this connection
+    let closed = loop {
+        match timeout(Duration::from_secs(10), first.next()).await.unwrap() {
+            Some(Ok(Message::Close(frame))) => break frame,
+            Some(Ok(_)) => continue,
+            _ => panic!("Connection dropped without a close frame"),
+        }
+    };
+    assert_eq!(u16::from(closed.unwrap().code), 4000);
+
+    let mut second = accept(&listener).await;
+    assert_eq!(receive(&mut second).await["op"], 6);
+}
+
+#[tokio::test]
+async fn answers_requested_heartbeats() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    send(&mut socket, ready_payload("session")).await;
+    send(&mut socket, serde_json::json!({"op": 1, "d": null, "s": null, "t": null})).await;
+
+    let heartbeat = timeout(Duration::from_secs(1), socket.next()).await.unwrap().unwrap().unwrap();
+    let heartbeat: serde_json::Value = serde_json::from_str(heartbeat.to_text().unwrap()).unwrap();
+
+    assert_eq!(heartbeat["op"], 1);
+    assert_eq!(heartbeat["d"], 1);
+    assert_eq!(bot.client.lock().await.sequence, Some(1));
+}
+
+#[tokio::test]
+async fn identifies_every_shard() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+
+    {
+        let mut client = bot.client.lock().await;
+        client.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+        client.gateway.shards = 2;
+        client.gateway.session_start_limit.max_concurrency = 2;
+    }
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    bot.add_event(GatewayEventBinding::Ready, Box::new(move |_, client| {
+        let sender = sender.clone();
+        async move {
+            sender.send(client.lock().await.shard_id()).unwrap();
+        }.boxed()
+    })).await;
+
+    let shard_manager = ShardManager::new(bot.client.clone(), bot.gateway_event_map.clone()).await;
+    tokio::spawn(async move {
+        let _ = shard_manager.run().await;
+    });
+
+    let mut identified = Vec::new();
+    let mut sockets = Vec::new();
+    for _ in 0..2 {
+        let mut socket = accept(&listener).await;
+        let identify = receive(&mut socket).await;
+
+        identified.push(identify["d"]["shard"].clone());
+        send(&mut socket, ready_payload("session")).await;
+        sockets.push(socket);
+    }
+
+    let mut ready_shards = vec![receiver.recv().await.unwrap(), receiver.recv().await.unwrap()];
+    ready_shards.sort_unstable();
+
+    assert!(identified.contains(&serde_json::json!([0, 2])));
+    assert!(identified.contains(&serde_json::json!([1, 2])));
+    assert_eq!(ready_shards, vec![0, 1]);
+}
+
+#[tokio::test]
+async fn sends_gateway_requests_through_the_running_shards() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+
+    {
+        let mut client = bot.client.lock().await;
+        client.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+        client.gateway.shards = 2;
+        client.gateway.session_start_limit.max_concurrency = 2;
+    }
+
+    let handle = bot.start().await;
+    assert_eq!(handle.shards().len(), 2);
+
+    // sockets in shard order, whichever connected first
+    let mut sockets = vec![None, None];
+    for _ in 0..2 {
+        let mut socket = accept(&listener).await;
+        let identify = receive(&mut socket).await;
+        send(&mut socket, ready_payload("session")).await;
+
+        let shard_id = identify["d"]["shard"][0].as_u64().unwrap() as usize;
+        sockets[shard_id] = Some(socket);
+    }
+    let mut sockets: Vec<MockSocket> = sockets.into_iter().map(Option::unwrap).collect();
+
+    handle
+        .update_presence(Status::Idle, vec![BotActivity::new("the shards", 3)], false, None)
+        .await
+        .unwrap();
+
+    for socket in sockets.iter_mut() {
+        let presence = receive(socket).await;
+        assert_eq!(presence["op"], 3);
+        assert_eq!(presence["d"]["status"], "idle");
+    }
+
+    // (41771983427338241 >> 22) % 2 is 1
+    let guild_id = Snowflake::String(String::from("41771983427338241"));
+    assert_eq!(ShardManager::shard_id_for_guild(&guild_id, 2), 1);
+
+    let members = handle.request_guild_members(guild_id, MemberQuery::Query(String::new()), 0, false);
+    let answer = async {
+        let request = receive(&mut sockets[1]).await;
+        assert_eq!(request["op"], 8);
+        send(&mut sockets[1], member_chunk(&request["d"]["nonce"], "1", 0, 1)).await;
+    };
+
+    let (members, _) = timeout(Duration::from_secs(5), futures::future::join(members, answer)).await.unwrap();
+    assert_eq!(members.unwrap().len(), 1);
+    assert_eq!(handle.latencies().await.len(), 2);
+}
+
+#[tokio::test]
+async fn waits_for_its_identify_turn_before_connecting() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+
+    {
+        let mut client = bot.client.lock().await;
+        client.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+        client.gateway.shards = 2;
+        client.gateway.session_start_limit.max_concurrency = 1;
+    }
+
+    let shard_manager = ShardManager::new(bot.client.clone(), bot.gateway_event_map.clone()).await;
+    tokio::spawn(async move {
+        let _ = shard_manager.run().await;
+    });
+
+    let mut first = accept(&listener).await;
+    let first_identify = receive(&mut first).await;
+    let identified_at = tokio::time::Instant::now();
+
+    // the other shard shares the bucket, so it mustn't sit on an open socket while it waits
+    assert!(timeout(Duration::from_secs(4), listener.accept()).await.is_err());
+
+    let mut second = accept(&listener).await;
+    let second_identify = receive(&mut second).await;
+
+    assert!(identified_at.elapsed() >= Duration::from_millis(4900));
+    assert_ne!(first_identify["d"]["shard"], second_identify["d"]["shard"]);
+}
+
+#[tokio::test]
+async fn waits_for_session_start_limit_reset() {
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+
+    let mut session_start_limit = bot.client.lock().await.gateway.session_start_limit.clone();
+    session_start_limit.remaining = 0;
+    session_start_limit.reset_after = 300;
+
+    let identify_queue = IdentifyQueue::new(&session_start_limit);
+    let started = tokio::time::Instant::now();
+
+    identify_queue.wait(0).await;
+
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(identify_queue.remaining().await, session_start_limit.total - 1);
+}
+
+#[tokio::test]
+#[allow(clippy::result_large_err)]
+async fn inflates_zlib_stream_frames() {
+    use flate2::{Compress, Compression, FlushCompress};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = Client::new(9, String::from("token"), vec![Intent::GUILDS]).compress(true);
+    let bot = mock_bot_from(&listener, Bot::from_client(client)).await;
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let (query_sender, query_receiver) = std::sync::mpsc::channel();
+    let mut socket = accept_hdr_async(stream, move |request: &tokio_tungstenite::tungstenite::handshake::server::Request, response| {
+        query_sender.send(request.uri().query().map(String::from)).unwrap();
+        Ok(response)
+    }).await.unwrap();
+
+    assert_eq!(query_receiver.recv().unwrap().as_deref(), Some("compress=zlib-stream"));
+
+    // one zlib context for the whole connection, like discord does
+    let mut compress = Compress::new(Compression::default(), true);
+    let mut deflate = |payload: serde_json::Value| {
+        let mut output = Vec::with_capacity(4096);
+        compress.compress_vec(payload.to_string().as_bytes(), &mut output, FlushCompress::Sync).unwrap();
+        output
+    };
+
+    let hello = deflate(serde_json::json!({"op": 10, "d": {"heartbeat_interval": 45000}, "s": null, "t": null}));
+    socket.send(Message::Binary(hello)).await.unwrap();
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    // a message can be spread over several frames
+    let ready = deflate(ready_payload("compressed"));
+    let (first, second) = ready.split_at(ready.len() / 2);
+    socket.send(Message::Binary(first.to_vec())).await.unwrap();
+    socket.send(Message::Binary(second.to_vec())).await.unwrap();
+
+    let reconnect = deflate(serde_json::json!({"op": 7, "d": null, "s": null, "t": null}));
+    socket.send(Message::Binary(reconnect)).await.unwrap();
+
+    let mut second = accept(&listener).await;
+    assert_eq!(receive(&mut second).await["op"], 6);
+    assert_eq!(bot.client.lock().await.session_id.as_deref(), Some("compressed"));
+}
+
+#[tokio::test]
+async fn speaks_etf_when_asked() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = Client::new(9, String::from("token"), vec![Intent::GUILDS]).encoding(Encoding::Etf);
+    let bot = mock_bot_from(&listener, Bot::from_client(client)).await;
+
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut socket = accept_async(stream).await.unwrap();
+
+    let hello = serde_json::json!({"op": 10, "d": {"heartbeat_interval": 45000}, "s": null, "t": null});
+    socket.send(Message::Binary(etf::to_vec(&hello).unwrap())).await.unwrap();
+
+    let identify = match timeout(Duration::from_secs(10), socket.next()).await.unwrap().unwrap().unwrap() {
+        Message::Binary(data) => etf::from_slice::<serde_json::Value>(&data).unwrap(),
+        message => panic!("Expected an etf identify, got {:?}", message),
+    };
+    assert_eq!(identify["op"], 2);
+    assert_eq!(identify["d"]["token"], "token");
+
+    socket.send(Message::Binary(etf::to_vec(&ready_payload("etf")).unwrap())).await.unwrap();
+    socket.send(Message::Binary(etf::to_vec(&serde_json::json!({"op": 7, "d": null, "s": null, "t": null})).unwrap())).await.unwrap();
+
+    let (stream, _) = listener.accept().await.unwrap();
+    drop(stream);
+    assert_eq!(bot.client.lock().await.session_id.as_deref(), Some("etf"));
+}
+
+#[tokio::test]
+async fn sends_presence_in_identify_and_updates() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let presence = GatewayPresence::new(Status::Idle, vec![BotActivity::new("the stars", 3)], false, None);
+    let bot = mock_bot_from(&listener, Bot::new(9, String::from("token"), vec![Intent::GUILDS], Some(presence))).await;
+
+    let mut socket = accept(&listener).await;
+    let identify = receive(&mut socket).await;
+
+    assert_eq!(identify["d"]["presence"]["status"], "idle");
+    assert_eq!(identify["d"]["presence"]["activities"][0]["name"], "the stars");
+    assert_eq!(identify["d"]["presence"]["activities"][0]["type"], 3);
+
+    Client::update_presence(bot.client.clone(), Status::Dnd, vec![BotActivity::new("chess", 0)], true, Some(1000))
+        .await
+        .unwrap();
+
+    let update = receive(&mut socket).await;
+    assert_eq!(update["op"], 3);
+    assert_eq!(update["d"]["status"], "dnd");
+    assert_eq!(update["d"]["activities"][0]["name"], "chess");
+    assert_eq!(update["d"]["afk"], true);
+    assert_eq!(update["d"]["since"], 1000);
+}
+
+fn member_chunk(nonce: &serde_json::Value, user_id: &str, chunk_index: u64, chunk_count: u64) -> serde_json::Value {
+    serde_json::json!({
+        "op": 0,
+        "s": 2 + chunk_index,
+        "t": "GUILD_MEMBERS_CHUNK",
+        "d": {
+            "guild_id": "41771983423143937",
+            "members": [{
+                "user": {"id": user_id, "username": "member", "discriminator": "0001"},
+                "roles": [],
+                "joined_at": "2015-04-26T06:26:56.936000+00:00",
+                "deaf": false,
+                "mute": false
+            }],
+            "chunk_index": chunk_index,
+            "chunk_count": chunk_count,
+            "nonce": nonce
+        }
+    })
+}
+
+#[tokio::test]
+async fn collects_requested_guild_member_chunks() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    let client = bot.client.clone();
+    let members = tokio::spawn(Client::request_guild_members(
+        client,
+        Snowflake::String(String::from("41771983423143937")),
+        MemberQuery::Query(String::new()),
+        0,
+        false,
+    ));
+
+    let request = receive(&mut socket).await;
+    assert_eq!(request["op"], 8);
+    assert_eq!(request["d"]["guild_id"], "41771983423143937");
+    assert_eq!(request["d"]["query"], "");
+
+    let nonce = request["d"]["nonce"].clone();
+    send(&mut socket, member_chunk(&nonce, "1", 0, 2)).await;
+    send(&mut socket, member_chunk(&serde_json::json!("someone else"), "2", 0, 1)).await;
+    send(&mut socket, member_chunk(&nonce, "3", 1, 2)).await;
+
+    let members = timeout(Duration::from_secs(5), members).await.unwrap().unwrap().unwrap();
+    let user_ids: Vec<String> = members.iter().map(|member| member.user.as_ref().unwrap().id.to_string()).collect();
+
+    assert_eq!(user_ids, vec!["1", "3"]);
+}
+
+#[tokio::test]
+async fn gives_up_on_unanswered_member_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = Client::new(9, String::from("token"), vec![Intent::GUILDS]).gateway_request_timeout(Duration::from_millis(300));
+    let bot = mock_bot_from(&listener, Bot::from_client(client)).await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    let guild_id = Snowflake::String(String::from("41771983423143937"));
+    let members = Client::request_guild_members(bot.client.clone(), guild_id, MemberQuery::Query(String::new()), 0, false);
+
+    let members = timeout(Duration::from_secs(5), members).await.unwrap();
+    assert!(matches!(members, Err(DiscordError::Timeout(_))));
+    assert_eq!(receive(&mut socket).await["op"], 8);
+}
+
+fn voice_state(user_id: &str, session_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "op": 0,
+        "s": 2,
+        "t": "VOICE_STATE_UPDATE",
+        "d": {
+            "guild_id": "41771983423143937",
+            "channel_id": "127121515262115840",
+            "user_id": user_id,
+            "session_id": session_id,
+            "deaf": false,
+            "mute": false,
+            "self_deaf": true,
+            "self_mute": false,
+            "self_video": false,
+            "suppress": false
+        }
+    })
+}
+
+#[tokio::test]
+async fn joins_voice_once_state_and_server_arrive() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+    send(&mut socket, ready_payload("session")).await;
+
+    let joining = tokio::spawn(Client::join_voice(
+        bot.client.clone(),
+        Snowflake::String(String::from("41771983423143937")),
+        Snowflake::String(String::from("127121515262115840")),
+        false,
+        true,
+    ));
+
+    let update = receive(&mut socket).await;
+    assert_eq!(update["op"], 4);
+    assert_eq!(update["d"]["channel_id"], "127121515262115840");
+    assert_eq!(update["d"]["self_deaf"], true);
+
+    // someone else's voice state shouldn't finish our join
+    send(&mut socket, voice_state("1", "not ours")).await;
+    send(&mut socket, serde_json::json!({
+        "op": 0,
+        "s": 3,
+        "t": "VOICE_SERVER_UPDATE",
+        "d": {"token": "voice token", "guild_id": "41771983423143937", "endpoint": "smart.loyal.discord.gg"}
+    })).await;
+    send(&mut socket, voice_state("80351110224678912", "voice session")).await;
+
+    let voice_session = timeout(Duration::from_secs(5), joining).await.unwrap().unwrap().unwrap();
+    assert_eq!(voice_session.session_id, "voice session");
+    assert_eq!(voice_session.token, "voice token");
+    assert_eq!(voice_session.endpoint, "smart.loyal.discord.gg");
+    assert_eq!(voice_session.user_id.to_string(), "80351110224678912");
+
+    Client::leave_voice(bot.client.clone(), Snowflake::String(String::from("41771983423143937"))).await.unwrap();
+
+    let leave = receive(&mut socket).await;
+    assert_eq!(leave["op"], 4);
+    assert!(leave["d"]["channel_id"].is_null());
+}
+
+#[tokio::test]
+async fn gives_up_on_ignored_voice_joins() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = Client::new(9, String::from("token"), vec![Intent::GUILDS]).gateway_request_timeout(Duration::from_millis(300));
+    let bot = mock_bot_from(&listener, Bot::from_client(client)).await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    let guild_id = Snowflake::String(String::from("41771983423143937"));
+    let channel_id = Snowflake::String(String::from("127121515262115840"));
+    let joined = Client::join_voice(bot.client.clone(), guild_id, channel_id, false, true);
+
+    let joined = timeout(Duration::from_secs(5), joined).await.unwrap();
+    assert!(matches!(joined, Err(DiscordError::Timeout(_))));
+    assert_eq!(receive(&mut socket).await["op"], 4);
+}
+
+#[tokio::test]
+async fn shuts_down_after_running_handlers_finish() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+    bot.client.lock().await.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    bot.add_event(GatewayEventBinding::Ready, Box::new(move |_, _| {
+        let sender = sender.clone();
+        async move {
+            sender.send("started").unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            sender.send("finished").unwrap();
+        }.boxed()
+    })).await;
+
+    let handle = bot.start().await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+    send(&mut socket, ready_payload("session")).await;
+    assert_eq!(receiver.recv().await, Some("started"));
+
+    timeout(Duration::from_secs(5), handle.shutdown()).await.unwrap().unwrap();
+    assert_eq!(receiver.try_recv(), Ok("finished"));
+
+    loop {
+        match timeout(Duration::from_secs(5), socket.next()).await.unwrap() {
+            Some(Ok(Message::Close(frame))) => {
+                assert_eq!(u16::from(frame.unwrap().code), 1000);
+                break;
+            }
+            Some(Ok(_)) => continue,
+            other => panic!("Expected a close frame, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn shuts_down_while_waiting_to_identify() {
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+
+    {
+        let mut client = bot.client.lock().await;
+        client.gateway.url = String::from("ws://127.0.0.1:1");
+        client.gateway.session_start_limit.remaining = 0;
+        client.gateway.session_start_limit.reset_after = 60 * 60 * 1000;
+    }
+
+    let handle = bot.start().await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    timeout(Duration::from_secs(1), handle.shutdown()).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn releases_waiting_requests_on_shutdown() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+    bot.client.lock().await.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    bot.add_event(GatewayEventBinding::Ready, Box::new(move |_, client| {
+        let sender = sender.clone();
+        async move {
+            let guild_id = Snowflake::String(String::from("41771983423143937"));
+            let members = Client::request_guild_members(client, guild_id, MemberQuery::Query(String::new()), 0, false).await;
+            sender.send(members.is_err()).unwrap();
+        }.boxed()
+    })).await;
+
+    let handle = bot.start().await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+    send(&mut socket, ready_payload("session")).await;
+    assert_eq!(receive(&mut socket).await["op"], 8);
+
+    // well inside the handler timeout, the handler only finishes if its request was dropped
+    timeout(Duration::from_secs(5), handle.shutdown()).await.unwrap().unwrap();
+    assert_eq!(receiver.try_recv(), Ok(true));
+}
+
+#[tokio::test]
+async fn skips_payloads_it_cannot_decode() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = mock_bot(&listener).await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    socket.send(Message::Text(String::from("{not json"))).await.unwrap();
+    send(&mut socket, ready_payload("session")).await;
+    send(&mut socket, serde_json::json!({"op": 1, "d": null, "s": null, "t": null})).await;
+
+    let heartbeat = timeout(Duration::from_secs(5), socket.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(heartbeat.to_text().unwrap()).unwrap()["op"], 1);
+    assert_eq!(bot.client.lock().await.session_id.as_deref(), Some("session"));
+}
+
+#[tokio::test]
+async fn reports_an_invalid_token() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+    bot.client.lock().await.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+
+    let handle = bot.start().await;
+
+    let mut socket = accept(&listener).await;
+    assert_eq!(receive(&mut socket).await["op"], 2);
+
+    let close_frame = CloseFrame {
+        code: CloseCode::Library(4004),
+        reason: "Authentication failed.".into(),
+    };
+    socket.send(Message::Close(Some(close_frame))).await.unwrap();
+
+    let stopped = timeout(Duration::from_secs(5), handle.wait()).await.unwrap();
+    assert!(matches!(stopped, Err(DiscordError::InvalidToken)));
+}
+
+#[tokio::test]
+async fn stops_every_shard_when_one_closes_for_good() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+
+    {
+        let mut client = bot.client.lock().await;
+        client.gateway.url = format!("ws://{}", listener.local_addr().unwrap());
+        client.gateway.shards = 2;
+        client.gateway.session_start_limit.max_concurrency = 2;
+    }
+
+    let handle = bot.start().await;
+
+    let mut closing = accept(&listener).await;
+    receive(&mut closing).await;
+    let mut running = accept(&listener).await;
+    receive(&mut running).await;
+    send(&mut running, ready_payload("session")).await;
+
+    let close_frame = CloseFrame {
+        code: CloseCode::Library(4004),
+        reason: "Authentication failed.".into(),
+    };
+    closing.send(Message::Close(Some(close_frame))).await.unwrap();
+
+    // the shard that was still fine is closed too instead of keeping the bot half alive
+    let closed = timeout(Duration::from_secs(5), async {
+        loop {
+            match running.next().await {
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(_)) => {}
+            }
+        }
+    });
+    assert!(closed.await.is_ok());
+
+    let stopped = timeout(Duration::from_secs(5), handle.wait()).await.unwrap();
+    assert!(matches!(stopped, Err(DiscordError::InvalidToken)));
+}
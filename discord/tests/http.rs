@@ -0,0 +1,753 @@
+extern crate celestialcord;
+
+use celestialcord::bot::Bot;
+use celestialcord::disc_objects::{
+    Channel, ChannelSettings, Embed, Emoji, Invite, InviteSettings, Message, MessageHistory, MessageReactions,
+    PermissionOverwrite, ReplyMessage, Snowflake, User, DISCORD_EPOCH,
+};
+use celestialcord::discord::{DiscordError, FieldError, HttpRequest, Intent};
+use celestialcord::ratelimit::{RateLimiter, Route};
+use futures::stream::TryStreamExt;
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::{sleep, Duration, Instant};
+
+#[derive(Debug)]
+struct MockRequest {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct MockResponse {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+fn respond(status: u16, body: serde_json::Value) -> MockResponse {
+    MockResponse {
+        status,
+        headers: Vec::new(),
+        body: body.to_string(),
+    }
+}
+
+fn rate_limited(mut response: MockResponse, bucket: &str, remaining: u64, reset_after: f64) -> MockResponse {
+    response.headers.push(("x-ratelimit-bucket", bucket.to_string()));
+    response.headers.push(("x-ratelimit-remaining", remaining.to_string()));
+    response.headers.push(("x-ratelimit-reset-after", reset_after.to_string()));
+    response
+}
+
+// answers each request with the next response in order, one connection per request
+async fn mock_api(responses: Vec<MockResponse>) -> (Bot, UnboundedReceiver<MockRequest>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let bot = Bot::new(9, String::from("token"), vec![Intent::GUILDS], None);
+    bot.client.lock().await.api_url = format!("http://{}", listener.local_addr().unwrap());
+
+    let (sender, receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        for response in responses {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+            let mut request_line = request_line.split_whitespace();
+
+            let method = request_line.next().unwrap().to_string();
+            let path = request_line.next().unwrap().to_string();
+
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => headers.insert(name.to_lowercase(), value.to_string()),
+                    None => break,
+                };
+            }
+
+            let length = headers.get("content-length").map(|length| length.parse().unwrap()).unwrap_or(0);
+            let mut body = vec![0u8; length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let _ = sender.send(MockRequest { method, path, headers, body });
+
+            let mut raw = format!(
+                "HTTP/1.1 {} Mock\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n",
+                response.status,
+                response.body.len()
+            );
+            for (name, value) in response.headers {
+                raw.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            raw.push_str("\r\n");
+            raw.push_str(&response.body);
+
+            stream.get_mut().write_all(raw.as_bytes()).await.unwrap();
+        }
+    });
+
+    (bot, receiver)
+}
+
+fn channel_id() -> Snowflake {
+    Snowflake::String(String::from("290926798999357250"))
+}
+
+fn message_json() -> serde_json::Value {
+    serde_json::json!({
+        "id": "334385199974967042",
+        "channel_id": "290926798999357250",
+        "author": {"id": "53908099506183680", "username": "Mason", "discriminator": "9999", "avatar": null},
+        "content": "Supa Hot",
+        "timestamp": "2017-07-11T17:27:07.299000+00:00",
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": [],
+        "pinned": false,
+        "type": 0
+    })
+}
+
+#[tokio::test]
+async fn sends_reply_messages() {
+    let (bot, mut requests) = mock_api(vec![respond(200, message_json())]).await;
+
+    let message = ReplyMessage::new(false)
+        .content_str("Supa Hot")
+        .send(channel_id(), bot.client.clone())
+        .await
+        .unwrap();
+
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/channels/290926798999357250/messages");
+    assert_eq!(request.headers["authorization"], "Bot token");
+
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["content"], "Supa Hot");
+    assert_eq!(message.content, "Supa Hot");
+}
+
+#[tokio::test]
+async fn decodes_api_errors() {
+    let (bot, _requests) = mock_api(vec![respond(403, serde_json::json!({"code": 50013, "message": "Missing Permissions"}))]).await;
+
+    let sent = ReplyMessage::new(false).content_str("hi").send(channel_id(), bot.client.clone()).await;
+
+    match sent {
+        Err(DiscordError::Api { status, code, message, errors }) => {
+            assert_eq!(status, 403);
+            assert_eq!(code, 50013);
+            assert_eq!(message, "Missing Permissions");
+            assert!(errors.is_none());
+        }
+        sent => panic!("Expected an api error, got {:?}", sent),
+    }
+}
+
+#[tokio::test]
+async fn keeps_the_field_error_tree() {
+    let body = serde_json::json!({
+        "code": 50035,
+        "message": "Invalid Form Body",
+        "errors": {
+            "embeds": {"0": {"title": {"_errors": [{"code": "BASE_TYPE_REQUIRED", "message": "This field is required"}]}}},
+            "content": {"_errors": [{"code": "BASE_TYPE_MAX_LENGTH", "message": "Must be 2000 or fewer in length."}]}
+        }
+    });
+    let (bot, _requests) = mock_api(vec![respond(400, body)]).await;
+
+    let sent = ReplyMessage::new(false)
+        .add_embed(Embed::new("", "", 0))
+        .send(channel_id(), bot.client.clone())
+        .await;
+
+    let errors = match sent {
+        Err(DiscordError::Api { code: 50035, errors: Some(errors), .. }) => errors,
+        sent => panic!("Expected invalid form body, got {:?}", sent),
+    };
+
+    let title = &errors.get("embeds").unwrap().get("0").unwrap().get("title").unwrap().errors;
+    assert_eq!(title[0].code, "BASE_TYPE_REQUIRED");
+
+    let flattened = errors.flatten();
+    assert!(flattened.contains(&(
+        String::from("embeds.0.title"),
+        FieldError {
+            code: String::from("BASE_TYPE_REQUIRED"),
+            message: String::from("This field is required"),
+        }
+    )));
+    assert!(flattened.iter().any(|(path, error)| path == "content" && error.code == "BASE_TYPE_MAX_LENGTH"));
+}
+
+#[tokio::test]
+async fn reports_an_invalid_token_from_rest() {
+    let (bot, _requests) = mock_api(vec![respond(401, serde_json::json!({"code": 0, "message": "401: Unauthorized"}))]).await;
+
+    let sent = ReplyMessage::new(false).content_str("hi").send(channel_id(), bot.client.clone()).await;
+    assert!(matches!(sent, Err(DiscordError::InvalidToken)));
+}
+
+#[test]
+fn maps_routes_to_buckets_by_major_parameter() {
+    let first = Route::new(&reqwest::Method::GET, "/channels/1/messages/2");
+    let second = Route::new(&reqwest::Method::GET, "/channels/1/messages/3");
+    let other_channel = Route::new(&reqwest::Method::GET, "/channels/4/messages/2?limit=100");
+
+    assert_eq!(first, second);
+    assert_eq!(first.route, "GET /channels/1/messages/:id");
+    assert_eq!(first.major, "channels/1");
+    assert_eq!(other_channel.major, "channels/4");
+
+    let reaction = Route::new(&reqwest::Method::PUT, "/channels/1/messages/2/reactions/%F0%9F%91%8D/@me");
+    assert_eq!(reaction.route, "PUT /channels/1/messages/:id/reactions/:reaction/@me");
+
+    let webhook = Route::new(&reqwest::Method::POST, "/webhooks/5/token");
+    assert_eq!(webhook.major, "webhooks/5/token");
+}
+
+#[tokio::test]
+async fn waits_for_an_exhausted_bucket_to_reset() {
+    let (bot, mut requests) = mock_api(vec![
+        rate_limited(respond(200, message_json()), "messages", 0, 0.4),
+        rate_limited(respond(200, message_json()), "messages", 4, 5.0),
+        rate_limited(respond(200, message_json()), "messages", 3, 5.0),
+    ])
+    .await;
+
+    let reply = ReplyMessage::new(false).content_str("hi");
+    let other_channel = Snowflake::String(String::from("1"));
+
+    reply.send(channel_id(), bot.client.clone()).await.unwrap();
+
+    // another channel has its own bucket, so it doesn't wait
+    let started = Instant::now();
+    reply.send(other_channel, bot.client.clone()).await.unwrap();
+    assert!(started.elapsed() < Duration::from_millis(300));
+
+    reply.send(channel_id(), bot.client.clone()).await.unwrap();
+    assert!(started.elapsed() >= Duration::from_millis(350));
+
+    assert_eq!(requests.recv().await.unwrap().path, "/channels/290926798999357250/messages");
+    assert_eq!(requests.recv().await.unwrap().path, "/channels/1/messages");
+    assert_eq!(requests.recv().await.unwrap().path, "/channels/290926798999357250/messages");
+}
+
+#[tokio::test]
+async fn queues_concurrent_sends_behind_the_bucket() {
+    let limited = |remaining, reset_after| {
+        let mut response = rate_limited(respond(200, message_json()), "messages", remaining, reset_after);
+        response.headers.push(("x-ratelimit-limit", String::from("2")));
+        response
+    };
+
+    let (bot, mut requests) = mock_api(vec![limited(1, 0.5), limited(0, 0.5), limited(1, 5.0), limited(0, 5.0)]).await;
+
+    let started = Instant::now();
+    let sends: Vec<_> = (0..4)
+        .map(|_| {
+            let client = bot.client.clone();
+            tokio::spawn(async move { ReplyMessage::new(false).content_str("hi").send(channel_id(), client).await })
+        })
+        .collect();
+
+    let mut sent_at = Vec::new();
+    for _ in 0..4 {
+        requests.recv().await.unwrap();
+        sent_at.push(started.elapsed());
+    }
+
+    for send in sends {
+        send.await.unwrap().unwrap();
+    }
+
+    // the first response shows the bucket, the second empties it, the rest wait for it to reset and refill
+    assert!(sent_at[1] < Duration::from_millis(300), "{:?}", sent_at);
+    assert!(sent_at[2] >= Duration::from_millis(450), "{:?}", sent_at);
+    assert!(sent_at[3] < Duration::from_millis(900), "{:?}", sent_at);
+}
+
+#[tokio::test]
+async fn retries_after_being_rate_limited() {
+    let (bot, mut requests) = mock_api(vec![
+        respond(429, serde_json::json!({"message": "You are being rate limited.", "retry_after": 0.3, "global": false})),
+        respond(200, message_json()),
+    ])
+    .await;
+
+    let started = Instant::now();
+    let message = ReplyMessage::new(false).content_str("hi").send(channel_id(), bot.client.clone()).await.unwrap();
+
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(message.content, "Supa Hot");
+    assert_eq!(requests.recv().await.unwrap().method, "POST");
+    assert_eq!(requests.recv().await.unwrap().method, "POST");
+}
+
+#[tokio::test]
+async fn pauses_every_route_on_a_global_rate_limit() {
+    let (bot, mut requests) = mock_api(vec![
+        respond(429, serde_json::json!({"message": "You are being rate limited.", "retry_after": 0.5, "global": true})),
+        respond(200, message_json()),
+        respond(200, message_json()),
+    ])
+    .await;
+
+    let client = bot.client.clone();
+    let limited = tokio::spawn(async move { ReplyMessage::new(false).content_str("hi").send(channel_id(), client).await });
+
+    requests.recv().await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let started = Instant::now();
+    ReplyMessage::new(false)
+        .content_str("hi")
+        .send(Snowflake::String(String::from("1")), bot.client.clone())
+        .await
+        .unwrap();
+
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    limited.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn stops_sending_after_too_many_invalid_requests() {
+    let forbidden = || respond(403, serde_json::json!({"code": 50013, "message": "Missing Permissions"}));
+    let (bot, mut requests) = mock_api(vec![forbidden(), forbidden(), forbidden()]).await;
+    bot.client.lock().await.rate_limiter = Arc::new(RateLimiter::new().invalid_request_threshold(2));
+
+    for _ in 0..2 {
+        let sent = ReplyMessage::new(false).content_str("hi").send(channel_id(), bot.client.clone()).await;
+        assert!(matches!(sent, Err(DiscordError::Api { status: 403, .. })));
+    }
+
+    let sent = ReplyMessage::new(false).content_str("hi").send(channel_id(), bot.client.clone()).await;
+    assert!(matches!(sent, Err(DiscordError::TooManyInvalidRequests(_))));
+
+    requests.recv().await.unwrap();
+    requests.recv().await.unwrap();
+    assert!(requests.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn sends_every_verb_with_query_and_audit_log_reason() {
+    let (bot, mut requests) = mock_api(vec![
+        respond(200, serde_json::json!({})),
+        no_content(),
+        no_content(),
+    ])
+    .await;
+
+    HttpRequest::str_new("/channels/1", bot.client.clone())
+        .await
+        .audit_log_reason("Renamed by a moderator: spam über alles")
+        .patch(Some(serde_json::json!({"name": "general"})))
+        .await
+        .unwrap();
+
+    HttpRequest::str_new("/guilds/2/members/3/roles/4", bot.client.clone()).await.put(None).await.unwrap();
+
+    HttpRequest::str_new("/guilds/2/bans/3", bot.client.clone())
+        .await
+        .query("delete_message_days", 7)
+        .delete(None)
+        .await
+        .unwrap();
+
+    let patch = requests.recv().await.unwrap();
+    assert_eq!(patch.method, "PATCH");
+    assert_eq!(patch.headers["x-audit-log-reason"], "Renamed%20by%20a%20moderator%3A%20spam%20%C3%BCber%20alles");
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&patch.body).unwrap()["name"], "general");
+
+    let put = requests.recv().await.unwrap();
+    assert_eq!(put.method, "PUT");
+    assert!(put.body.is_empty());
+    assert!(!put.headers.contains_key("x-audit-log-reason"));
+
+    let delete = requests.recv().await.unwrap();
+    assert_eq!(delete.method, "DELETE");
+    assert_eq!(delete.path, "/guilds/2/bans/3?delete_message_days=7");
+}
+
+#[tokio::test]
+async fn uploads_files_as_multipart() {
+    let (bot, mut requests) = mock_api(vec![respond(200, message_json())]).await;
+
+    let csv: &[u8] = b"day,messages\nmonday,42\n";
+    let reply = ReplyMessage::new(false)
+        .content_str("Weekly report")
+        .add_embed(Embed::new("Activity", "Messages per day", 0x5865f2).image("attachment://chart.png"))
+        .add_file("chart.png", vec![0x89, b'P', b'N', b'G'])
+        .describe_file("chart.png", "Bar chart of messages per day")
+        .add_file_reader("report.csv", csv)
+        .await
+        .unwrap();
+
+    reply.send(channel_id(), bot.client.clone()).await.unwrap();
+
+    let request = requests.recv().await.unwrap();
+    assert!(request.headers["content-type"].starts_with("multipart/form-data; boundary="));
+
+    let body = String::from_utf8_lossy(&request.body);
+    assert!(body.contains("name=\"files[0]\"; filename=\"chart.png\""));
+    assert!(body.contains("name=\"files[1]\"; filename=\"report.csv\""));
+    assert!(body.contains("day,messages\nmonday,42\n"));
+
+    let payload_json = body.split("name=\"payload_json\"\r\n\r\n").nth(1).unwrap();
+    let payload_json: serde_json::Value = serde_json::Deserializer::from_str(payload_json)
+        .into_iter()
+        .next()
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(payload_json["content"], "Weekly report");
+    assert_eq!(payload_json["embeds"][0]["image"]["url"], "attachment://chart.png");
+    assert_eq!(payload_json["attachments"][0]["id"], 0);
+    assert_eq!(payload_json["attachments"][0]["description"], "Bar chart of messages per day");
+    assert_eq!(payload_json["attachments"][1]["filename"], "report.csv");
+    assert_eq!(payload_json["attachments"][1]["description"], serde_json::Value::Null);
+}
+
+fn no_content() -> MockResponse {
+    MockResponse {
+        status: 204,
+        headers: Vec::new(),
+        body: String::new(),
+    }
+}
+
+// a message id from the given number of milliseconds ago
+fn message_id_from(ago: u64) -> Snowflake {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    Snowflake::Integer((now - ago - DISCORD_EPOCH) << 22)
+}
+
+#[tokio::test]
+async fn fetches_edits_crossposts_and_deletes_messages() {
+    let mut edited = message_json();
+    edited["content"] = serde_json::json!("Supa Hot Fire");
+
+    let (bot, mut requests) = mock_api(vec![
+        respond(200, message_json()),
+        respond(200, edited),
+        respond(200, message_json()),
+        no_content(),
+    ])
+    .await;
+
+    let message = Message::fetch(channel_id(), Snowflake::Integer(334385199974967042), bot.client.clone())
+        .await
+        .unwrap();
+
+    let edited = message
+        .edit(&ReplyMessage::new(false).content_str("Supa Hot Fire"), bot.client.clone())
+        .await
+        .unwrap();
+    assert_eq!(edited.content, "Supa Hot Fire");
+
+    message.crosspost(bot.client.clone()).await.unwrap();
+    message.delete(bot.client.clone()).await.unwrap();
+
+    let fetch = requests.recv().await.unwrap();
+    assert_eq!((fetch.method.as_str(), fetch.path.as_str()), ("GET", "/channels/290926798999357250/messages/334385199974967042"));
+
+    let edit = requests.recv().await.unwrap();
+    assert_eq!((edit.method.as_str(), edit.path.as_str()), ("PATCH", "/channels/290926798999357250/messages/334385199974967042"));
+    let edit_body: serde_json::Value = serde_json::from_slice(&edit.body).unwrap();
+    assert_eq!(edit_body["content"], "Supa Hot Fire");
+    assert!(edit_body.get("embeds").is_none());
+
+    let crosspost = requests.recv().await.unwrap();
+    assert_eq!(
+        (crosspost.method.as_str(), crosspost.path.as_str()),
+        ("POST", "/channels/290926798999357250/messages/334385199974967042/crosspost")
+    );
+
+    let delete = requests.recv().await.unwrap();
+    assert_eq!((delete.method.as_str(), delete.path.as_str()), ("DELETE", "/channels/290926798999357250/messages/334385199974967042"));
+}
+
+#[tokio::test]
+async fn checks_bulk_deletes_before_sending() {
+    // the example from discord's docs
+    assert_eq!(Snowflake::String(String::from("175928847299117063")).timestamp(), Some(1462015105796));
+
+    let (bot, mut requests) = mock_api(vec![no_content()]).await;
+    let day = 24 * 60 * 60 * 1000;
+
+    let too_few = Message::bulk_delete(channel_id(), vec![message_id_from(0)], bot.client.clone()).await;
+    assert!(matches!(too_few, Err(DiscordError::InvalidRequest(_))));
+
+    let too_many = Message::bulk_delete(channel_id(), (0..101).map(message_id_from).collect(), bot.client.clone()).await;
+    assert!(matches!(too_many, Err(DiscordError::InvalidRequest(_))));
+
+    let too_old = vec![message_id_from(day), message_id_from(15 * day)];
+    let too_old = Message::bulk_delete(channel_id(), too_old, bot.client.clone()).await;
+    assert!(matches!(too_old, Err(DiscordError::InvalidRequest(_))));
+
+    let recent = vec![message_id_from(day), message_id_from(13 * day)];
+    Message::bulk_delete(channel_id(), recent.clone(), bot.client.clone()).await.unwrap();
+
+    // only the valid bulk delete reached discord
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.path, "/channels/290926798999357250/messages/bulk-delete");
+
+    let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+    assert_eq!(body["messages"], serde_json::json!(recent));
+}
+
+// a page of messages as discord sends it, newest first
+fn message_page(newest: u64, count: u64) -> serde_json::Value {
+    let messages: Vec<serde_json::Value> = (0..count)
+        .map(|index| {
+            let mut message = message_json();
+            message["id"] = serde_json::json!((newest - index).to_string());
+            message
+        })
+        .collect();
+
+    serde_json::json!(messages)
+}
+
+#[tokio::test]
+async fn pages_through_message_history() {
+    let (bot, mut requests) = mock_api(vec![
+        respond(200, message_page(1000, 100)),
+        respond(200, message_page(900, 3)),
+        respond(200, message_page(2002, 2)),
+    ])
+    .await;
+
+    let before: Vec<Message> = MessageHistory::new(channel_id(), bot.client.clone())
+        .before(Snowflake::Integer(1001))
+        .try_collect()
+        .await
+        .unwrap();
+
+    assert_eq!(before.len(), 103);
+    assert_eq!(before[0].id, Snowflake::String(String::from("1000")));
+    assert_eq!(before[102].id, Snowflake::String(String::from("898")));
+
+    let first = requests.recv().await.unwrap();
+    assert_eq!(first.path, "/channels/290926798999357250/messages?limit=100&before=1001");
+
+    // the next page starts before the oldest message of the last, and a short page is the end
+    let second = requests.recv().await.unwrap();
+    assert_eq!(second.path, "/channels/290926798999357250/messages?limit=100&before=901");
+
+    let after: Vec<Message> = MessageHistory::new(channel_id(), bot.client.clone())
+        .after(Snowflake::Integer(2000))
+        .try_collect()
+        .await
+        .unwrap();
+
+    let ids: Vec<Snowflake> = after.into_iter().map(|message| message.id).collect();
+    assert_eq!(ids, vec![Snowflake::String(String::from("2001")), Snowflake::String(String::from("2002"))]);
+    assert_eq!(requests.recv().await.unwrap().path, "/channels/290926798999357250/messages?limit=100&after=2000");
+}
+
+#[tokio::test]
+async fn reacts_with_unicode_and_custom_emoji() {
+    let (bot, mut requests) = mock_api((0..6).map(|_| no_content()).collect()).await;
+
+    let emoji: Emoji = serde_json::from_value(serde_json::json!({"id": "41771983429993937", "name": "LUL"})).unwrap();
+    let reactions = MessageReactions::new(channel_id(), Snowflake::Integer(334385199974967042), bot.client.clone());
+
+    reactions.create("👍").await.unwrap();
+    reactions.create(&emoji).await.unwrap();
+    reactions.delete_own("👍").await.unwrap();
+    reactions.delete_user(emoji.clone(), Snowflake::Integer(53908099506183680)).await.unwrap();
+    reactions.delete_all_for("👍").await.unwrap();
+    reactions.delete_all().await.unwrap();
+
+    let base = "/channels/290926798999357250/messages/334385199974967042/reactions";
+    let expected = [
+        ("PUT", format!("{}/%F0%9F%91%8D/@me", base)),
+        ("PUT", format!("{}/LUL:41771983429993937/@me", base)),
+        ("DELETE", format!("{}/%F0%9F%91%8D/@me", base)),
+        ("DELETE", format!("{}/LUL:41771983429993937/53908099506183680", base)),
+        ("DELETE", format!("{}/%F0%9F%91%8D", base)),
+        ("DELETE", base.to_string()),
+    ];
+
+    for (method, path) in expected {
+        let request = requests.recv().await.unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), (method, path.as_str()));
+    }
+}
+
+#[tokio::test]
+async fn pages_through_reaction_users() {
+    let users = |first: u64, count: u64| {
+        let users: Vec<serde_json::Value> = (first..first + count)
+            .map(|id| serde_json::json!({"id": id.to_string(), "username": "Mason", "discriminator": "9999", "avatar": null}))
+            .collect();
+        serde_json::json!(users)
+    };
+
+    let (bot, mut requests) = mock_api(vec![respond(200, users(1, 100)), respond(200, users(101, 20))]).await;
+
+    let reacted: Vec<User> = MessageReactions::new(channel_id(), Snowflake::Integer(334385199974967042), bot.client.clone())
+        .users("🔥")
+        .try_collect()
+        .await
+        .unwrap();
+
+    assert_eq!(reacted.len(), 120);
+    assert_eq!(reacted[119].id, Snowflake::String(String::from("120")));
+
+    let base = "/channels/290926798999357250/messages/334385199974967042/reactions/%F0%9F%94%A5";
+    assert_eq!(requests.recv().await.unwrap().path, format!("{}?limit=100", base));
+    assert_eq!(requests.recv().await.unwrap().path, format!("{}?limit=100&after=100", base));
+}
+
+fn channel_json(id: &str, channel_type: u64, name: &str) -> serde_json::Value {
+    serde_json::json!({"id": id, "type": channel_type, "guild_id": "41771983423143937", "name": name})
+}
+
+fn body_json(request: &MockRequest) -> serde_json::Value {
+    serde_json::from_slice(&request.body).unwrap()
+}
+
+#[tokio::test]
+async fn provisions_channels_with_audit_log_reasons() {
+    let (bot, mut requests) = mock_api(vec![
+        respond(201, channel_json("1", 4, "Staff")),
+        respond(201, channel_json("2", 0, "mod-log")),
+        respond(200, channel_json("2", 0, "mod-log")),
+        no_content(),
+        no_content(),
+        respond(200, channel_json("2", 0, "mod-log")),
+    ])
+    .await;
+
+    let guild_id = Snowflake::Integer(41771983423143937);
+    let everyone = Snowflake::Integer(41771983423143937);
+
+    let category = Channel::create(guild_id.clone(), &ChannelSettings::new("Staff", 4), Some("Server setup"), bot.client.clone())
+        .await
+        .unwrap();
+
+    let settings = ChannelSettings::new("mod-log", 0)
+        .parent(category.id.clone())
+        .add_permission_overwrite(PermissionOverwrite::role(everyone.clone(), 0, 1024));
+    let channel = Channel::create(guild_id, &settings, None, bot.client.clone()).await.unwrap();
+
+    channel
+        .modify(&ChannelSettings::default().topic("Moderation actions"), Some("Describe the channel"), bot.client.clone())
+        .await
+        .unwrap();
+    channel
+        .edit_permission(&PermissionOverwrite::member(Snowflake::Integer(53908099506183680), 1024, 0), Some("Let Mason in"), bot.client.clone())
+        .await
+        .unwrap();
+    channel.delete_permission(everyone, None, bot.client.clone()).await.unwrap();
+    channel.delete(Some("Cleaning up"), bot.client.clone()).await.unwrap();
+
+    let create_category = requests.recv().await.unwrap();
+    assert_eq!((create_category.method.as_str(), create_category.path.as_str()), ("POST", "/guilds/41771983423143937/channels"));
+    assert_eq!(create_category.headers["x-audit-log-reason"], "Server%20setup");
+    assert_eq!(body_json(&create_category), serde_json::json!({"name": "Staff", "type": 4}));
+
+    let create_channel = requests.recv().await.unwrap();
+    assert!(!create_channel.headers.contains_key("x-audit-log-reason"));
+    assert_eq!(body_json(&create_channel)["parent_id"], "1");
+    assert_eq!(
+        body_json(&create_channel)["permission_overwrites"],
+        serde_json::json!([{"id": 41771983423143937u64, "type": 0, "allow": "0", "deny": "1024"}])
+    );
+
+    // only what was set is sent, so modifying doesn't clear the rest of the channel
+    let modify = requests.recv().await.unwrap();
+    assert_eq!((modify.method.as_str(), modify.path.as_str()), ("PATCH", "/channels/2"));
+    assert_eq!(body_json(&modify), serde_json::json!({"topic": "Moderation actions"}));
+
+    let edit_permission = requests.recv().await.unwrap();
+    assert_eq!((edit_permission.method.as_str(), edit_permission.path.as_str()), ("PUT", "/channels/2/permissions/53908099506183680"));
+    assert_eq!(body_json(&edit_permission), serde_json::json!({"allow": "1024", "deny": "0", "type": 1}));
+    assert_eq!(edit_permission.headers["x-audit-log-reason"], "Let%20Mason%20in");
+
+    let delete_permission = requests.recv().await.unwrap();
+    assert_eq!((delete_permission.method.as_str(), delete_permission.path.as_str()), ("DELETE", "/channels/2/permissions/41771983423143937"));
+
+    let delete = requests.recv().await.unwrap();
+    assert_eq!((delete.method.as_str(), delete.path.as_str()), ("DELETE", "/channels/2"));
+    assert_eq!(delete.headers["x-audit-log-reason"], "Cleaning%20up");
+}
+
+#[tokio::test]
+async fn types_pins_follows_and_invites() {
+    let invite = serde_json::json!({
+        "code": "0vCdhLbwjZZTWZLD",
+        "guild": {"id": "41771983423143937", "name": "Discord Developers"},
+        "channel": {"id": "290926798999357250", "type": 0, "name": "general"},
+        "max_age": 3600,
+        "max_uses": 5,
+        "uses": 0,
+        "temporary": false,
+        "created_at": "2016-03-31T19:15:39.954000+00:00"
+    });
+
+    let (bot, mut requests) = mock_api(vec![
+        no_content(),
+        respond(200, serde_json::json!([message_json()])),
+        no_content(),
+        respond(200, serde_json::json!({"channel_id": "290926798999357250", "webhook_id": "1"})),
+        respond(200, invite.clone()),
+        respond(200, serde_json::json!([invite])),
+    ])
+    .await;
+
+    let channel: Channel = serde_json::from_value(channel_json("290926798999357250", 5, "announcements")).unwrap();
+
+    channel.trigger_typing(bot.client.clone()).await.unwrap();
+    assert_eq!(channel.pins(bot.client.clone()).await.unwrap()[0].content, "Supa Hot");
+    channel.pin(Snowflake::Integer(334385199974967042), Some("Important"), bot.client.clone()).await.unwrap();
+
+    let followed = channel.follow(Snowflake::Integer(80351110224678912), bot.client.clone()).await.unwrap();
+    assert_eq!(followed.webhook_id, Snowflake::String(String::from("1")));
+
+    let settings = InviteSettings::new().max_age(3600).max_uses(5).unique(true);
+    let created: Invite = channel.create_invite(&settings, Some("Onboarding"), bot.client.clone()).await.unwrap();
+    assert_eq!(created.code, "0vCdhLbwjZZTWZLD");
+    assert_eq!(created.guild.unwrap().name.as_deref(), Some("Discord Developers"));
+
+    let invites = channel.invites(bot.client.clone()).await.unwrap();
+    assert_eq!(invites[0].max_uses, Some(5));
+
+    let expected = [
+        ("POST", "/channels/290926798999357250/typing"),
+        ("GET", "/channels/290926798999357250/pins"),
+        ("PUT", "/channels/290926798999357250/pins/334385199974967042"),
+        ("POST", "/channels/290926798999357250/followers"),
+        ("POST", "/channels/290926798999357250/invites"),
+        ("GET", "/channels/290926798999357250/invites"),
+    ];
+
+    let mut sent = Vec::new();
+    for (method, path) in expected {
+        let request = requests.recv().await.unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), (method, path));
+        sent.push(request);
+    }
+
+    assert_eq!(sent[2].headers["x-audit-log-reason"], "Important");
+    assert_eq!(body_json(&sent[3])["webhook_channel_id"], 80351110224678912u64);
+    assert_eq!(body_json(&sent[4]), serde_json::json!({"max_age": 3600, "max_uses": 5, "unique": true}));
+    assert_eq!(sent[4].headers["x-audit-log-reason"], "Onboarding");
+}
@@ -0,0 +1,72 @@
+extern crate celestialcord;
+
+use celestialcord::rtp::{EncryptionMode, RtpHeader, RtpReceiver, RtpSender, FRAME_SAMPLES};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+
+const SECRET_KEY: [u8; 32] = [7; 32];
+
+// two udp sockets connected to each other, standing in for us and the voice server
+async fn loopback() -> (Arc<UdpSocket>, Arc<UdpSocket>) {
+    let ours = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let theirs = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    ours.connect(theirs.local_addr().unwrap()).await.unwrap();
+    theirs.connect(ours.local_addr().unwrap()).await.unwrap();
+
+    (Arc::new(ours), Arc::new(theirs))
+}
+
+#[test]
+fn frames_rtp_headers_big_endian() {
+    let header = RtpHeader {
+        sequence: 0x0102,
+        timestamp: 0x03040506,
+        ssrc: 0x0708090a,
+    };
+
+    let bytes = header.to_bytes();
+    assert_eq!(bytes, [0x80, 0x78, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a]);
+    assert_eq!(RtpHeader::from_bytes(&bytes), Some(header));
+
+    // rtcp receiver reports share the socket but aren't audio
+    assert_eq!(RtpHeader::from_bytes(&[0x81, 0xc9, 0, 7, 0, 0, 0, 1, 0, 0, 0, 0]), None);
+}
+
+#[tokio::test]
+async fn sends_and_receives_every_encryption_mode() {
+    for mode in [EncryptionMode::Normal, EncryptionMode::Suffix, EncryptionMode::Lite] {
+        let (ours, theirs) = loopback().await;
+
+        let mut sender = RtpSender::new(ours, 42, mode, &SECRET_KEY);
+        let receiver = RtpReceiver::new(theirs, mode, &SECRET_KEY);
+        let (sequence, timestamp) = (sender.sequence, sender.timestamp);
+
+        sender.send(b"first opus frame").await.unwrap();
+        sender.send(b"second opus frame").await.unwrap();
+
+        let first = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        let second = timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+
+        assert_eq!(first.payload, b"first opus frame", "{:?}", mode);
+        assert_eq!(second.payload, b"second opus frame", "{:?}", mode);
+
+        assert_eq!(first.header.ssrc, 42);
+        assert_eq!(first.header.sequence, sequence);
+        assert_eq!(first.header.timestamp, timestamp);
+        assert_eq!(second.header.sequence, sequence.wrapping_add(1));
+        assert_eq!(second.header.timestamp, timestamp.wrapping_add(FRAME_SAMPLES));
+    }
+}
+
+#[tokio::test]
+async fn rejects_packets_encrypted_with_another_key() {
+    let (ours, theirs) = loopback().await;
+
+    let mut sender = RtpSender::new(ours, 42, EncryptionMode::Lite, &[1; 32]);
+    let receiver = RtpReceiver::new(theirs, EncryptionMode::Lite, &SECRET_KEY);
+
+    let packet = sender.encrypt(b"opus frame").unwrap();
+    assert!(receiver.decrypt(&packet).is_err());
+}
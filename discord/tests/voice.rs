@@ -0,0 +1,204 @@
+extern crate celestialcord;
+
+use celestialcord::disc_objects::Snowflake;
+use celestialcord::discord::VoiceSession;
+use celestialcord::rtp::EncryptionMode;
+use celestialcord::voice::{discover_ip, VoiceConnection};
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::{accept_async, WebSocketStream};
+
+type MockSocket = WebSocketStream<TcpStream>;
+
+fn voice_session(listener: &TcpListener) -> VoiceSession {
+    VoiceSession {
+        guild_id: Snowflake::String(String::from("41771983423143937")),
+        user_id: Snowflake::String(String::from("80351110224678912")),
+        session_id: String::from("voice session"),
+        token: String::from("voice token"),
+        endpoint: format!("ws://{}", listener.local_addr().unwrap()),
+    }
+}
+
+async fn accept(listener: &TcpListener, heartbeat_interval: f64) -> MockSocket {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut socket = accept_async(stream).await.unwrap();
+
+    send(&mut socket, 8, serde_json::json!({"heartbeat_interval": heartbeat_interval})).await;
+    socket
+}
+
+async fn send(socket: &mut MockSocket, op: u64, data: serde_json::Value) {
+    let payload = serde_json::json!({"op": op, "d": data});
+    socket.send(Message::Text(payload.to_string())).await.unwrap();
+}
+
+async fn receive_op(socket: &mut MockSocket, op: u64) -> serde_json::Value {
+    loop {
+        let message = timeout(Duration::from_secs(10), socket.next())
+            .await
+            .expect("Client did not send anything")
+            .unwrap()
+            .unwrap();
+
+        let payload: serde_json::Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        if payload["op"] == op {
+            return payload["d"].clone();
+        }
+    }
+}
+
+// answers ip discovery the way discord does, with the address and port the request came from
+async fn answer_discovery(udp: &UdpSocket, ssrc: u32) {
+    let mut request = [0u8; 74];
+    let (received, from) = udp.recv_from(&mut request).await.unwrap();
+
+    assert_eq!(received, 74);
+    assert_eq!(request[0..2], 1u16.to_be_bytes());
+    assert_eq!(request[2..4], 70u16.to_be_bytes());
+    assert_eq!(request[4..8], ssrc.to_be_bytes());
+
+    let mut response = [0u8; 74];
+    response[0..2].copy_from_slice(&2u16.to_be_bytes());
+    response[2..4].copy_from_slice(&70u16.to_be_bytes());
+    response[4..8].copy_from_slice(&ssrc.to_be_bytes());
+    response[8..17].copy_from_slice(b"127.0.0.1");
+    response[72..74].copy_from_slice(&from.port().to_be_bytes());
+
+    udp.send_to(&response, from).await.unwrap();
+}
+
+async fn handshake(listener: &TcpListener, udp: &UdpSocket, heartbeat_interval: f64) -> (VoiceConnection, MockSocket) {
+    let connecting = tokio::spawn(VoiceConnection::connect(voice_session(listener)));
+    let mut socket = accept(listener, heartbeat_interval).await;
+
+    let identify = receive_op(&mut socket, 0).await;
+    assert_eq!(identify["server_id"], "41771983423143937");
+    assert_eq!(identify["user_id"], "80351110224678912");
+    assert_eq!(identify["session_id"], "voice session");
+    assert_eq!(identify["token"], "voice token");
+
+    send(&mut socket, 2, serde_json::json!({
+        "ssrc": 7,
+        "ip": "127.0.0.1",
+        "port": udp.local_addr().unwrap().port(),
+        "modes": ["aead_aes256_gcm", "xsalsa20_poly1305", "xsalsa20_poly1305_suffix", "xsalsa20_poly1305_lite"]
+    })).await;
+
+    answer_discovery(udp, 7).await;
+
+    let select_protocol = receive_op(&mut socket, 1).await;
+    assert_eq!(select_protocol["protocol"], "udp");
+    assert_eq!(select_protocol["data"]["address"], "127.0.0.1");
+    assert_eq!(select_protocol["data"]["mode"], "xsalsa20_poly1305_lite");
+
+    send(&mut socket, 4, serde_json::json!({"mode": "xsalsa20_poly1305_lite", "secret_key": vec![1; 32]})).await;
+
+    let connection = timeout(Duration::from_secs(5), connecting).await.unwrap().unwrap().unwrap();
+    assert_eq!(select_protocol["data"]["port"], connection.udp.local_addr().unwrap().port());
+
+    (connection, socket)
+}
+
+#[tokio::test]
+async fn completes_the_voice_handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (connection, _socket) = handshake(&listener, &udp, 45000.0).await;
+
+    assert_eq!(connection.ssrc, 7);
+    assert_eq!(connection.mode, EncryptionMode::Lite);
+    assert_eq!(connection.secret_key, [1; 32]);
+    assert_eq!(connection.server_address, udp.local_addr().unwrap());
+    assert_eq!(connection.external_address, connection.udp.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn heartbeats_and_resumes_the_voice_websocket() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let (connection, mut socket) = handshake(&listener, &udp, 200.0).await;
+
+    let nonce = receive_op(&mut socket, 3).await;
+    send(&mut socket, 6, nonce).await;
+
+    for _ in 0..50 {
+        if connection.latency().await.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert!(connection.latency().await.is_some());
+
+    // a dropped voice websocket resumes instead of needing a new voice session
+    socket.close(None).await.unwrap();
+
+    let mut resumed = accept(&listener, 200.0).await;
+    let resume = receive_op(&mut resumed, 7).await;
+
+    assert_eq!(resume["server_id"], "41771983423143937");
+    assert_eq!(resume["session_id"], "voice session");
+    assert_eq!(resume["token"], "voice token");
+
+    send(&mut resumed, 9, serde_json::Value::Null).await;
+    receive_op(&mut resumed, 3).await;
+    assert!(!connection.is_closed());
+}
+
+#[tokio::test]
+async fn heartbeats_while_the_handshake_is_slow() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let connecting = tokio::spawn(VoiceConnection::connect(voice_session(&listener)));
+    let mut socket = accept(&listener, 100.0).await;
+    receive_op(&mut socket, 0).await;
+
+    // keep the client waiting for ready across a few heartbeat intervals
+    for _ in 0..3 {
+        let nonce = receive_op(&mut socket, 3).await;
+        send(&mut socket, 6, nonce).await;
+    }
+
+    send(&mut socket, 2, serde_json::json!({
+        "ssrc": 7,
+        "ip": "127.0.0.1",
+        "port": udp.local_addr().unwrap().port(),
+        "modes": ["xsalsa20_poly1305_lite"]
+    })).await;
+
+    answer_discovery(&udp, 7).await;
+    receive_op(&mut socket, 1).await;
+    send(&mut socket, 4, serde_json::json!({"mode": "xsalsa20_poly1305_lite", "secret_key": vec![1; 32]})).await;
+
+    let connection = timeout(Duration::from_secs(5), connecting).await.unwrap().unwrap().unwrap();
+
+    let nonce = receive_op(&mut socket, 3).await;
+    send(&mut socket, 6, nonce).await;
+    receive_op(&mut socket, 3).await;
+    assert!(!connection.is_closed());
+}
+
+#[tokio::test]
+async fn asks_again_when_discovery_goes_unanswered() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    udp.connect(server.local_addr().unwrap()).await.unwrap();
+
+    let answering = tokio::spawn(async move {
+        // the first request gets lost on the way
+        let mut request = [0u8; 74];
+        server.recv_from(&mut request).await.unwrap();
+
+        answer_discovery(&server, 7).await;
+    });
+
+    let external_address = timeout(Duration::from_secs(5), discover_ip(&udp, 7)).await.unwrap().unwrap();
+
+    assert_eq!(external_address, udp.local_addr().unwrap());
+    answering.await.unwrap();
+}